@@ -0,0 +1,73 @@
+// File: src\bin\speedtest-server.rs
+// Author: Hadi Cahyadi <cumulus13@gmail.com>
+// Date: 2026-02-09
+// Description: Local speedtest server for offline and lab testing
+// License: MIT
+
+use clap::Parser;
+use colored::*;
+use speedtest::server::{serve, ServerOptions};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// Local server speaking the speedtest.net protocol, for offline and lab testing
+#[derive(Parser, Debug)]
+#[command(author = "Hadi Cahyadi <cumulus13@gmail.com>")]
+#[command(about = "Local server speaking the speedtest.net protocol, for offline and lab testing")]
+struct Args {
+    /// Address to listen on
+    #[arg(long, default_value = "0.0.0.0:8080")]
+    bind: String,
+
+    /// Download bandwidth limit in Mbit/s (shared by all connections)
+    #[arg(long, value_name = "MBPS")]
+    download_rate: Option<f64>,
+
+    /// Upload bandwidth limit in Mbit/s (shared by all connections)
+    #[arg(long, value_name = "MBPS")]
+    upload_rate: Option<f64>,
+
+    /// Latency in milliseconds added before every response
+    #[arg(long, value_name = "MS", default_value = "0")]
+    latency: u64,
+
+    /// Test length in seconds advertised to clients
+    #[arg(long, value_name = "SECS", default_value = "10")]
+    test_length: u64,
+
+    /// Base URL advertised in the server list (defaults to the request Host header)
+    #[arg(long, value_name = "URL")]
+    public_url: Option<String>,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+
+    let to_bits = |mbps: f64| (mbps * 1_000_000.0) as u64;
+    let options = ServerOptions {
+        download_rate: args.download_rate.map(to_bits),
+        upload_rate: args.upload_rate.map(to_bits),
+        latency: Duration::from_millis(args.latency),
+        test_length: args.test_length,
+        public_url: args.public_url,
+    };
+
+    let listener = match TcpListener::bind(&args.bind).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("{} Unable to listen on {}: {}", "ERROR:".red().bold(), args.bind, e);
+            std::process::exit(1);
+        }
+    };
+
+    println!(
+        "{}",
+        format!("Speedtest server listening on http://{}", args.bind).green()
+    );
+
+    if let Err(e) = serve(listener, options).await {
+        eprintln!("{} {}", "ERROR:".red().bold(), e);
+        std::process::exit(1);
+    }
+}
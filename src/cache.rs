@@ -0,0 +1,148 @@
+// File: src\cache.rs
+// Author: Hadi Cahyadi <cumulus13@gmail.com>
+// Date: 2026-02-09
+// Description: On-disk cache for the configuration and server list
+// License: MIT
+
+use crate::error::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Default time an entry is considered fresh
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(3600);
+
+#[derive(Serialize, Deserialize)]
+struct Entry<T> {
+    saved_at: u64,
+    data: T,
+}
+
+/// JSON files keyed by name, each stamped with the time it was written
+#[derive(Debug, Clone)]
+pub struct Cache {
+    dir: PathBuf,
+    ttl: Duration,
+    refresh: bool,
+}
+
+impl Cache {
+    pub fn new<P: Into<PathBuf>>(dir: P, ttl: Duration) -> Self {
+        Self {
+            dir: dir.into(),
+            ttl,
+            refresh: false,
+        }
+    }
+
+    /// `$XDG_CACHE_HOME/speedtest`, falling back to the platform cache directory
+    pub fn default_dir() -> Option<PathBuf> {
+        let env_dir = |name: &str| {
+            std::env::var_os(name)
+                .filter(|v| !v.is_empty())
+                .map(PathBuf::from)
+        };
+
+        let base = env_dir("XDG_CACHE_HOME").or_else(|| {
+            if cfg!(windows) {
+                env_dir("LOCALAPPDATA")
+            } else if cfg!(target_os = "macos") {
+                env_dir("HOME").map(|home| home.join("Library").join("Caches"))
+            } else {
+                env_dir("HOME").map(|home| home.join(".cache"))
+            }
+        })?;
+
+        Some(base.join("speedtest"))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Ignore fresh entries so everything is fetched again and rewritten
+    pub fn set_refresh(&mut self, refresh: bool) {
+        self.refresh = refresh;
+    }
+
+    /// Return the entry if it is younger than the TTL
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        if self.refresh {
+            return None;
+        }
+
+        let entry = self.read::<T>(key)?;
+        let age = now().saturating_sub(entry.saved_at);
+        (age < self.ttl.as_secs()).then_some(entry.data)
+    }
+
+    /// Return the entry whatever its age, for when fetching it failed
+    pub fn get_stale<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        self.read::<T>(key).map(|entry| entry.data)
+    }
+
+    pub fn put<T: Serialize>(&self, key: &str, data: &T) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+
+        let entry = Entry {
+            saved_at: now(),
+            data,
+        };
+
+        // Write then rename so a concurrent run never reads a partial file
+        let path = self.path(key);
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        std::fs::write(&tmp, serde_json::to_vec(&entry)?)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    fn read<T: DeserializeOwned>(&self, key: &str) -> Option<Entry<T>> {
+        let contents = std::fs::read(self.path(key)).ok()?;
+        serde_json::from_slice(&contents).ok()
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+}
+
+/// Build a file-safe cache key from a name and whatever the entry depends on
+pub fn cache_key(name: &str, parts: &[&str]) -> String {
+    format!("{}-{:x}", name, md5::compute(parts.join("\n")))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_ttl_and_refresh() {
+        let dir = std::env::temp_dir().join(format!("speedtest-cache-{}", std::process::id()));
+        let key = cache_key("servers", &["http://a.example/servers.php"]);
+
+        let mut cache = Cache::new(&dir, DEFAULT_CACHE_TTL);
+        assert_eq!(cache.get::<Vec<u32>>(&key), None);
+
+        cache.put(&key, &vec![1u32, 2, 3]).unwrap();
+        assert_eq!(cache.get::<Vec<u32>>(&key), Some(vec![1, 2, 3]));
+
+        cache.set_refresh(true);
+        assert_eq!(cache.get::<Vec<u32>>(&key), None);
+        assert_eq!(cache.get_stale::<Vec<u32>>(&key), Some(vec![1, 2, 3]));
+
+        let expired = Cache::new(&dir, Duration::ZERO);
+        assert_eq!(expired.get::<Vec<u32>>(&key), None);
+        assert_eq!(expired.get_stale::<Vec<u32>>(&key), Some(vec![1, 2, 3]));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -17,8 +17,8 @@ pub enum SpeedtestError {
     #[error("Invalid server ID type: {0}")]
     InvalidServerIdType(String),
 
-    #[error("No matched servers found")]
-    NoMatchedServers,
+    #[error("No matched servers found: {0}")]
+    NoMatchedServers(String),
 
     #[error("Speedtest mini server connection failure: {0}")]
     MiniConnectFailure(String),
@@ -41,6 +41,18 @@ pub enum SpeedtestError {
     #[error("Missing best server")]
     MissingBestServer,
 
+    #[error("Invalid source address: {0}")]
+    InvalidSourceAddress(String),
+
+    #[error("Invalid network interface: {0}")]
+    InvalidInterface(String),
+
+    #[error("Invalid proxy: {0}")]
+    InvalidProxy(String),
+
+    #[error("Invalid location: {0}")]
+    InvalidLocation(String),
+
     #[error("CLI error: {0}")]
     CliError(String),
 
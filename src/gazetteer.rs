@@ -0,0 +1,109 @@
+// File: src\gazetteer.rs
+// Author: Hadi Cahyadi <cumulus13@gmail.com>
+// Date: 2026-02-09
+// Description: Small bundled gazetteer for manual client location lookup
+// License: MIT
+
+/// City, country, ISO country code, latitude, longitude
+const CITIES: &[(&str, &str, &str, f64, f64)] = &[
+    ("Amsterdam", "Netherlands", "NL", 52.3676, 4.9041),
+    ("Athens", "Greece", "GR", 37.9838, 23.7275),
+    ("Auckland", "New Zealand", "NZ", -36.8485, 174.7633),
+    ("Bangkok", "Thailand", "TH", 13.7563, 100.5018),
+    ("Barcelona", "Spain", "ES", 41.3874, 2.1686),
+    ("Beijing", "China", "CN", 39.9042, 116.4074),
+    ("Berlin", "Germany", "DE", 52.5200, 13.4050),
+    ("Bogota", "Colombia", "CO", 4.7110, -74.0721),
+    ("Brussels", "Belgium", "BE", 50.8503, 4.3517),
+    ("Bucharest", "Romania", "RO", 44.4268, 26.1025),
+    ("Budapest", "Hungary", "HU", 47.4979, 19.0402),
+    ("Buenos Aires", "Argentina", "AR", -34.6037, -58.3816),
+    ("Cairo", "Egypt", "EG", 30.0444, 31.2357),
+    ("Cape Town", "South Africa", "ZA", -33.9249, 18.4241),
+    ("Chicago", "United States", "US", 41.8781, -87.6298),
+    ("Copenhagen", "Denmark", "DK", 55.6761, 12.5683),
+    ("Dallas", "United States", "US", 32.7767, -96.7970),
+    ("Delhi", "India", "IN", 28.7041, 77.1025),
+    ("Denver", "United States", "US", 39.7392, -104.9903),
+    ("Dubai", "United Arab Emirates", "AE", 25.2048, 55.2708),
+    ("Dublin", "Ireland", "IE", 53.3498, -6.2603),
+    ("Frankfurt", "Germany", "DE", 50.1109, 8.6821),
+    ("Helsinki", "Finland", "FI", 60.1699, 24.9384),
+    ("Hong Kong", "Hong Kong", "HK", 22.3193, 114.1694),
+    ("Istanbul", "Turkey", "TR", 41.0082, 28.9784),
+    ("Jakarta", "Indonesia", "ID", -6.2088, 106.8456),
+    ("Johannesburg", "South Africa", "ZA", -26.2041, 28.0473),
+    ("Kuala Lumpur", "Malaysia", "MY", 3.1390, 101.6869),
+    ("Lagos", "Nigeria", "NG", 6.5244, 3.3792),
+    ("Lima", "Peru", "PE", -12.0464, -77.0428),
+    ("Lisbon", "Portugal", "PT", 38.7223, -9.1393),
+    ("London", "United Kingdom", "GB", 51.5074, -0.1278),
+    ("Los Angeles", "United States", "US", 34.0522, -118.2437),
+    ("Madrid", "Spain", "ES", 40.4168, -3.7038),
+    ("Manila", "Philippines", "PH", 14.5995, 120.9842),
+    ("Melbourne", "Australia", "AU", -37.8136, 144.9631),
+    ("Mexico City", "Mexico", "MX", 19.4326, -99.1332),
+    ("Miami", "United States", "US", 25.7617, -80.1918),
+    ("Milan", "Italy", "IT", 45.4642, 9.1900),
+    ("Montreal", "Canada", "CA", 45.5017, -73.5673),
+    ("Moscow", "Russia", "RU", 55.7558, 37.6173),
+    ("Mumbai", "India", "IN", 19.0760, 72.8777),
+    ("Nairobi", "Kenya", "KE", -1.2921, 36.8219),
+    ("New York", "United States", "US", 40.7128, -74.0060),
+    ("Osaka", "Japan", "JP", 34.6937, 135.5023),
+    ("Oslo", "Norway", "NO", 59.9139, 10.7522),
+    ("Paris", "France", "FR", 48.8566, 2.3522),
+    ("Prague", "Czech Republic", "CZ", 50.0755, 14.4378),
+    ("Rome", "Italy", "IT", 41.9028, 12.4964),
+    ("San Francisco", "United States", "US", 37.7749, -122.4194),
+    ("Santiago", "Chile", "CL", -33.4489, -70.6693),
+    ("Sao Paulo", "Brazil", "BR", -23.5505, -46.6333),
+    ("Seattle", "United States", "US", 47.6062, -122.3321),
+    ("Seoul", "South Korea", "KR", 37.5665, 126.9780),
+    ("Shanghai", "China", "CN", 31.2304, 121.4737),
+    ("Singapore", "Singapore", "SG", 1.3521, 103.8198),
+    ("Stockholm", "Sweden", "SE", 59.3293, 18.0686),
+    ("Surabaya", "Indonesia", "ID", -7.2575, 112.7521),
+    ("Sydney", "Australia", "AU", -33.8688, 151.2093),
+    ("Taipei", "Taiwan", "TW", 25.0330, 121.5654),
+    ("Tokyo", "Japan", "JP", 35.6762, 139.6503),
+    ("Toronto", "Canada", "CA", 43.6532, -79.3832),
+    ("Vancouver", "Canada", "CA", 49.2827, -123.1207),
+    ("Vienna", "Austria", "AT", 48.2082, 16.3738),
+    ("Warsaw", "Poland", "PL", 52.2297, 21.0122),
+    ("Washington", "United States", "US", 38.9072, -77.0369),
+    ("Zurich", "Switzerland", "CH", 47.3769, 8.5417),
+];
+
+/// Look up the coordinates of a "City" or "City, Country" location
+///
+/// Matching is case-insensitive and the country may be a name or ISO code.
+pub fn lookup(query: &str) -> Option<(f64, f64)> {
+    let mut parts = query.splitn(2, ',');
+    let city = parts.next()?.trim();
+    let country = parts.next().map(str::trim).filter(|c| !c.is_empty());
+
+    CITIES
+        .iter()
+        .find(|(name, country_name, code, _, _)| {
+            name.eq_ignore_ascii_case(city)
+                && country.is_none_or(|c| {
+                    country_name.eq_ignore_ascii_case(c) || code.eq_ignore_ascii_case(c)
+                })
+        })
+        .map(|&(_, _, _, lat, lon)| (lat, lon))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(lookup("Jakarta, Indonesia"), Some((-6.2088, 106.8456)));
+        assert_eq!(lookup("london, gb"), Some((51.5074, -0.1278)));
+        assert_eq!(lookup("Tokyo"), Some((35.6762, 139.6503)));
+        assert_eq!(lookup("Paris, Texas"), None);
+        assert_eq!(lookup("Atlantis"), None);
+    }
+}
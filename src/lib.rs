@@ -4,12 +4,17 @@
 // Description: 
 // License: MIT
 
+pub mod cache;
 pub mod error;
+pub mod gazetteer;
 pub mod http;
 pub mod models;
+pub mod server;
 pub mod speedtest;
 pub mod utils;
 
+pub use cache::Cache;
 pub use error::{Result, SpeedtestError};
+pub use http::{HttpClient, HttpOptions};
 pub use models::*;
 pub use speedtest::Speedtest;
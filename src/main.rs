@@ -7,7 +7,11 @@
 use clap::{Parser, ArgAction};
 use colored::*;
 use indicatif::{ProgressBar, ProgressStyle};
-use speedtest::{Result, Speedtest, SpeedtestError, SpeedtestResults};
+use regex::Regex;
+use speedtest::{
+    gazetteer, Bufferbloat, Cache, Config, Endpoints, FamilyComparison, FamilyResult, HttpOptions, IpFamily, LatencyStats, ProbeOptions, Result, Server,
+    ServerFilter, servers_to_csv, Speedtest, SpeedtestError, SpeedtestResults, TransferProgress,
+};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration; 
@@ -66,6 +70,14 @@ struct Args {
     #[arg(long)]
     list: bool,
 
+    /// Maximum number of servers shown by --list
+    #[arg(long, value_name = "N", default_value = "100")]
+    list_limit: usize,
+
+    /// Measure and show the latency of each server in --list, sorted by latency
+    #[arg(long, requires = "list")]
+    ping: bool,
+
     /// Specify a server ID to test against (can be used multiple times)
     #[arg(long, value_name = "ID")]
     server: Vec<u32>,
@@ -74,14 +86,135 @@ struct Args {
     #[arg(long, value_name = "ID")]
     exclude: Vec<u32>,
 
+    /// Only use servers in this country, by name or ISO code (can be used multiple times)
+    #[arg(long, value_name = "COUNTRY")]
+    country: Vec<String>,
+
+    /// Only use servers whose sponsor matches this regular expression
+    #[arg(long, value_name = "REGEX")]
+    sponsor: Option<String>,
+
+    /// Only use servers whose name (usually the city) matches this regular expression
+    #[arg(long, value_name = "REGEX")]
+    name: Option<String>,
+
+    /// Only use servers at most this many km away
+    #[arg(long, value_name = "KM")]
+    max_distance: Option<f64>,
+
+    /// Only use servers at least this many km away
+    #[arg(long, value_name = "KM")]
+    min_distance: Option<f64>,
+
+    /// Number of closest servers probed for latency to pick the best one
+    #[arg(long, value_name = "N", default_value = "5")]
+    candidates: usize,
+
+    /// Latency requests per candidate server (the median is used)
+    #[arg(long, value_name = "N", default_value = "3")]
+    ping_attempts: usize,
+
+    /// Timeout in seconds of each latency request (default: --timeout)
+    #[arg(long, value_name = "SECONDS")]
+    ping_timeout: Option<f64>,
+
+    /// Number of servers probed for latency at once
+    #[arg(long, value_name = "N")]
+    ping_concurrency: Option<usize>,
+
+    /// Latency samples taken against the selected server for jitter and statistics (0 to skip)
+    #[arg(long, value_name = "N", default_value = "10")]
+    latency_samples: usize,
+
+    /// Measure latency under load during the download and upload tests
+    #[arg(long)]
+    bufferbloat: bool,
+
+    /// Milliseconds between latency probes with --bufferbloat
+    #[arg(long, value_name = "MS", default_value = "250", requires = "bufferbloat")]
+    bufferbloat_interval: u64,
+
     /// URL of the Speedtest Mini server
     #[arg(long)]
     mini: Option<String>,
 
+    /// Load the server list from a speedtest.net XML file or a JSON array of servers
+    #[arg(long, value_name = "PATH", conflicts_with = "mini")]
+    servers_file: Option<String>,
+
+    /// Latitude to measure server distances from instead of the geolocated one
+    #[arg(long, requires = "lon", allow_negative_numbers = true)]
+    lat: Option<f64>,
+
+    /// Longitude to measure server distances from instead of the geolocated one
+    #[arg(long, requires = "lat", allow_negative_numbers = true)]
+    lon: Option<f64>,
+
+    /// Measure server distances from a known city, e.g. "Jakarta, Indonesia"
+    #[arg(long, value_name = "CITY", conflicts_with_all = ["lat", "lon"])]
+    location: Option<String>,
+
+    /// Do not fetch the speedtest.net configuration, use local defaults instead
+    /// (requires --mini, --servers-file or --servers-url)
+    #[arg(long)]
+    no_config: bool,
+
+    /// Always fetch the configuration and server list instead of using the cache
+    #[arg(long, conflicts_with = "refresh_cache")]
+    no_cache: bool,
+
+    /// Fetch the configuration and server list again and update the cache
+    #[arg(long)]
+    refresh_cache: bool,
+
+    /// Seconds the cached configuration and server list stay fresh
+    #[arg(long, value_name = "SECONDS", default_value = "3600")]
+    cache_ttl: u64,
+
     /// Source IP address to bind to
     #[arg(long)]
     source: Option<String>,
 
+    /// Network interface to bind to (e.g. eth1)
+    #[arg(long)]
+    interface: Option<String>,
+
+    /// Only use IPv4 for the whole test
+    #[arg(long, conflicts_with = "ipv6")]
+    ipv4: bool,
+
+    /// Only use IPv6 for the whole test
+    #[arg(long)]
+    ipv6: bool,
+
+    /// Proxy to send all requests through (http://, https:// or socks5://)
+    #[arg(long, value_name = "URL")]
+    proxy: Option<String>,
+
+    /// JSON file with config_url, server_urls, share_url and share_result_url endpoints
+    #[arg(long, value_name = "PATH")]
+    endpoints_file: Option<String>,
+
+    /// URL of the speedtest configuration (overrides the endpoints file)
+    #[arg(long, value_name = "URL")]
+    config_url: Option<String>,
+
+    /// URL of a server list, can be used multiple times (overrides the endpoints file)
+    #[arg(long = "servers-url", value_name = "URL")]
+    servers_urls: Vec<String>,
+
+    /// Fetch every server list concurrently and merge them instead of using the first one
+    #[arg(long, conflicts_with_all = ["mini", "servers_file"])]
+    merge_servers: bool,
+
+    /// URL of the results sharing API (overrides the endpoints file)
+    #[arg(long, value_name = "URL")]
+    share_url: Option<String>,
+
+    /// Base URL of the shared result images (overrides the endpoints file)
+    #[arg(long, value_name = "URL")]
+    share_result_url: Option<String>,
+
     /// HTTP timeout in seconds
     #[arg(long, default_value = "10")]
     timeout: u64,
@@ -94,6 +227,11 @@ struct Args {
     #[arg(long)]
     no_pre_allocate: bool,
 
+    /// Run the test over both IPv4 and IPv6 and compare the results in a table,
+    /// or in a single combined JSON document with --json
+    #[arg(long, conflicts_with_all = ["ipv4", "ipv6", "csv", "simple", "share"])]
+    compare_families: bool,
+
     /// Enable debug output
     #[arg(long, hide = true)]
     debug: bool,
@@ -126,6 +264,18 @@ fn run() -> Result<()> {
         ));
     }
 
+    if args.candidates == 0 || args.ping_attempts == 0 || args.ping_concurrency == Some(0) {
+        return Err(SpeedtestError::CliError(
+            "--candidates, --ping-attempts and --ping-concurrency must be at least 1".to_string(),
+        ));
+    }
+
+    if args.ping_timeout.is_some_and(|t| !t.is_finite() || t <= 0.0) {
+        return Err(SpeedtestError::CliError(
+            "--ping-timeout must be a positive number of seconds".to_string(),
+        ));
+    }
+
     if args.csv_delimiter.len() != 1 {
         return Err(SpeedtestError::CliError(
             "--csv-delimiter must be a single character".to_string(),
@@ -139,6 +289,16 @@ fn run() -> Result<()> {
         return Ok(());
     }
 
+    if args.no_config
+        && args.mini.is_none()
+        && args.servers_file.is_none()
+        && args.servers_urls.is_empty()
+    {
+        return Err(SpeedtestError::CliError(
+            "--no-config requires --mini, --servers-file or --servers-url".to_string(),
+        ));
+    }
+
     let quiet = args.simple || args.csv || args.json;
 
     // Setup signal handler for Ctrl+C
@@ -151,53 +311,158 @@ fn run() -> Result<()> {
     })
     .expect("Error setting Ctrl-C handler");
 
+    let ip_family = if args.ipv4 {
+        Some(IpFamily::Ipv4)
+    } else if args.ipv6 {
+        Some(IpFamily::Ipv6)
+    } else {
+        None
+    };
+
     // Initialize speedtest
     if !quiet {
-        println!("{}", "Retrieving speedtest.net configuration...".cyan());
+        if args.no_config {
+            println!("{}", "Using local configuration...".cyan());
+        } else {
+            println!("{}", "Retrieving speedtest.net configuration...".cyan());
+        }
     }
 
-    let mut speedtest = Speedtest::new(args.timeout, args.secure, args.source)?;
-    
-    let config = speedtest.get_config()?;
+    let http_options = HttpOptions {
+        timeout: args.timeout,
+        secure: args.secure,
+        source_address: args.source.clone(),
+        interface: args.interface.clone(),
+        ip_family,
+        proxy: args.proxy.clone(),
+    };
+
+    let mut endpoints = match args.endpoints_file {
+        Some(ref path) => Endpoints::from_file(path)?,
+        None => Endpoints::default(),
+    };
+    if let Some(ref url) = args.config_url {
+        endpoints.config_url = url.clone();
+    }
+    if !args.servers_urls.is_empty() {
+        endpoints.server_urls = args.servers_urls.clone();
+    }
+    if let Some(ref url) = args.share_url {
+        endpoints.share_url = url.clone();
+    }
+    if let Some(ref url) = args.share_result_url {
+        endpoints.share_result_url = url.clone();
+    }
+
+    let location = match (args.lat, args.lon, args.location.as_deref()) {
+        (Some(lat), Some(lon), _) => Some((lat, lon)),
+        (_, _, Some(name)) => Some(gazetteer::lookup(name).ok_or_else(|| {
+            SpeedtestError::CliError(format!("Unknown location: {}", name))
+        })?),
+        _ => None,
+    };
+
+    let mut speedtest = Speedtest::with_options(http_options.clone())?;
+    speedtest.set_debug(args.debug);
+    speedtest.set_endpoints(endpoints);
+    speedtest.set_merge_servers(args.merge_servers);
+    speedtest.set_server_filter(server_filter(&args)?);
+    speedtest.set_probe_options(probe_options(&args));
+    speedtest.set_loaded_latency(loaded_latency_interval(&args));
+    if !args.no_cache {
+        speedtest.set_cache(Cache::default_dir().map(|dir| {
+            let mut cache = Cache::new(dir, Duration::from_secs(args.cache_ttl));
+            cache.set_refresh(args.refresh_cache);
+            cache
+        }));
+    }
+    if let Some((lat, lon)) = location {
+        speedtest.set_location(lat, lon)?;
+    }
+
+    let config = if args.no_config {
+        speedtest.use_local_config()?
+    } else {
+        speedtest.get_config()?
+    };
 
     if !quiet {
-        println!(
-            "{}",
-            format!(
-                "Testing from {} ({})...",
-                config.client.isp, config.client.ip
-            )
-            .green()
-        );
+        let from = if config.client.isp.is_empty() {
+            config.client.ip.clone()
+        } else {
+            format!("{} ({})", config.client.isp, config.client.ip)
+        };
+        println!("{}", format!("Testing from {}...", from).green());
     }
 
     // Handle server list request
     if args.list {
         if !quiet {
-            println!("{}", "Retrieving speedtest.net server list...".cyan());
+            if args.servers_file.is_some() {
+                println!("{}", "Loading server list from file...".cyan());
+            } else {
+                println!("{}", "Retrieving speedtest.net server list...".cyan());
+            }
         }
         
-        speedtest.get_servers(None, None)?;
+        match args.servers_file {
+            Some(ref path) => speedtest.load_servers_file(path, None, None)?,
+            None => speedtest.get_servers(None, None)?,
+        };
         
         let mut all_servers: Vec<_> = speedtest
-            .get_closest_servers(100)?
+            .get_closest_servers(args.list_limit)?
             .to_vec();
         
         all_servers.sort_by(|a, b| a.d.partial_cmp(&b.d).unwrap());
 
-        for server in all_servers {
-            println!(
-                "{:>5}) {} ({}, {}) [{:.2} km]",
-                server.id, server.sponsor, server.name, server.country, server.d
-            );
+        if args.ping {
+            if !quiet {
+                println!("{}", format!("Measuring latency of {} servers...", all_servers.len()).cyan());
+            }
+            speedtest.ping_servers(&mut all_servers);
+            // Fastest first, unreachable servers last
+            all_servers.sort_by(|a, b| {
+                b.reachable.cmp(&a.reachable).then(a.latency.total_cmp(&b.latency))
+            });
+        }
+
+        if args.json {
+            println!("{}", serde_json::to_string_pretty(&all_servers)?);
+        } else if args.csv {
+            let delimiter = args.csv_delimiter.chars().next().unwrap();
+            print!("{}", servers_to_csv(&all_servers, delimiter)?);
+        } else {
+            for server in all_servers {
+                let latency = match server.reachable {
+                    Some(true) => format!(" {:.3} ms", server.latency),
+                    Some(false) => " unreachable".to_string(),
+                    None => String::new(),
+                };
+                println!(
+                    "{:>5}) {} ({}, {}) [{:.2} km]{}",
+                    server.id, server.sponsor, server.name, server.country, server.d, latency
+                );
+            }
         }
         return Ok(());
     }
 
     // Get servers
-    if args.mini.is_none() {
+    if let Some(ref mini) = args.mini {
         if !quiet {
-            println!("{}", "Retrieving speedtest.net server list...".cyan());
+            println!("{}", "Retrieving Speedtest Mini server information...".cyan());
+        }
+
+        let servers = speedtest.set_mini_server(mini)?.to_vec();
+        speedtest.get_best_server(Some(&servers))?;
+    } else {
+        if !quiet {
+            if args.servers_file.is_some() {
+                println!("{}", "Loading server list from file...".cyan());
+            } else {
+                println!("{}", "Retrieving speedtest.net server list...".cyan());
+            }
         }
 
         let server_ids = if !args.server.is_empty() {
@@ -212,7 +477,10 @@ fn run() -> Result<()> {
             None
         };
 
-        speedtest.get_servers(server_ids, exclude)?;
+        match args.servers_file {
+            Some(ref path) => speedtest.load_servers_file(path, server_ids, exclude)?,
+            None => speedtest.get_servers(server_ids, exclude)?,
+        };
 
         if !quiet {
             if args.server.len() == 1 {
@@ -223,10 +491,6 @@ fn run() -> Result<()> {
         }
 
         speedtest.get_best_server(None)?;
-    } else {
-        return Err(SpeedtestError::CliError(
-            "Mini server support not yet implemented".to_string(),
-        ));
     }
 
     // let best_server = speedtest.get_best_server(None)?.clone();
@@ -248,8 +512,9 @@ fn run() -> Result<()> {
     // results.ping = best_server.latency;
     // results.server = best_server;
 
-    // Clone best_server BEFORE calling get_results() to avoid borrow conflict
-    let best_server = speedtest.get_best_server(None)?.clone();  // CLONE HERE
+    let mut results = speedtest.get_results()
+        .ok_or_else(|| SpeedtestError::Unknown("Failed to get results".to_string()))?;
+    let best_server = results.server.clone();
     if !quiet {
         println!(
             "{}",
@@ -260,10 +525,19 @@ fn run() -> Result<()> {
             .green()
         );
     }
-    let mut results = speedtest.get_results()
-        .ok_or_else(|| SpeedtestError::Unknown("Failed to get results".to_string()))?;
     results.ping = best_server.latency;
-    results.server = best_server;  // Store cloned server in results
+
+    if args.compare_families {
+        return compare_families(&args, &http_options, &speedtest, &best_server, quiet);
+    }
+
+    if args.latency_samples > 0 {
+        let stats = *speedtest.measure_latency(args.latency_samples)?;
+        if !quiet {
+            print_latency_stats(&stats);
+        }
+        results.latency = Some(stats);
+    }
 
     // Perform download test
     // if !args.no_download {
@@ -348,21 +622,28 @@ fn run() -> Result<()> {
             }
         };
         
-        let download_speed = speedtest.download(callback, threads)?;
-        results.download = download_speed;
+        let divisor = if args.bytes { 8.0 } else { 1.0 };
+        let unit = if args.bytes { "byte" } else { "bit" };
+        let pb_for_progress = pb.clone();
+        let progress = move |p: &TransferProgress| {
+            if let Some(pb) = &pb_for_progress {
+                pb.set_message(format!("{:.2} M{}/s", (p.rate / 1_000_000.0) / divisor, unit));
+            }
+        };
+
+        let download_result = speedtest.download_with_progress(callback, progress, threads)?;
+        results.set_download(&download_result);
         
         // Now pb is still usable here
         if let Some(pb) = pb {
             pb.finish_and_clear();
         }
         
-        let divisor = if args.bytes { 8.0 } else { 1.0 };
-        let unit = if args.bytes { "byte" } else { "bit" };
         if !quiet {
             println!(
                 "{} {:.2} M{}/s",
                 "Download:".green().bold(),
-                (download_speed / 1_000_000.0) / divisor,
+                (download_result.speed / 1_000_000.0) / divisor,
                 unit
             );
         }
@@ -455,21 +736,28 @@ fn run() -> Result<()> {
             }
         };
         
-        let upload_speed = speedtest.upload(callback, threads, pre_allocate)?;
-        results.upload = upload_speed;
+        let divisor = if args.bytes { 8.0 } else { 1.0 };
+        let unit = if args.bytes { "byte" } else { "bit" };
+        let pb_for_progress = pb.clone();
+        let progress = move |p: &TransferProgress| {
+            if let Some(pb) = &pb_for_progress {
+                pb.set_message(format!("{:.2} M{}/s", (p.rate / 1_000_000.0) / divisor, unit));
+            }
+        };
+
+        let upload_result = speedtest.upload_with_progress(callback, progress, threads, pre_allocate)?;
+        results.set_upload(&upload_result);
         
         // Now pb is still usable here
         if let Some(pb) = pb {
             pb.finish_and_clear();
         }
         
-        let divisor = if args.bytes { 8.0 } else { 1.0 };
-        let unit = if args.bytes { "byte" } else { "bit" };
         if !quiet {
             println!(
                 "{} {:.2} M{}/s",
                 "Upload:".green().bold(),
-                (upload_speed / 1_000_000.0) / divisor,
+                (upload_result.speed / 1_000_000.0) / divisor,
                 unit
             );
         }
@@ -477,12 +765,29 @@ fn run() -> Result<()> {
         println!("{}", "Skipping upload test".yellow());
     }
 
+    if let Some(ref bufferbloat) = results.bufferbloat {
+        if !quiet {
+            print_bufferbloat(bufferbloat);
+        }
+    }
+
+    if args.share {
+        if !quiet {
+            println!("{}", "Sharing results...".cyan());
+        }
+        let endpoints = speedtest.endpoints().clone();
+        results.share_with_url(speedtest.client(), &endpoints.share_url, &endpoints.share_result_url)?;
+    }
+
     // Output results
     if args.simple {
         let divisor = if args.bytes { 8.0 } else { 1.0 };
         let unit = if args.bytes { "byte" } else { "bit" };
         
         println!("Ping: {:.3} ms", results.ping);
+        if let Some(ref latency) = results.latency {
+            println!("Jitter: {:.3} ms", latency.jitter);
+        }
         println!(
             "Download: {:.2} M{}/s",
             (results.download / 1_000_000.0) / divisor,
@@ -493,6 +798,9 @@ fn run() -> Result<()> {
             (results.upload / 1_000_000.0) / divisor,
             unit
         );
+        if let Some(ref bufferbloat) = results.bufferbloat {
+            println!("Bufferbloat: {}", bufferbloat.grade);
+        }
     } else if args.csv {
         let delimiter = args.csv_delimiter.chars().next().unwrap();
         println!("{}", results.to_csv(delimiter)?);
@@ -500,8 +808,197 @@ fn run() -> Result<()> {
         println!("{}", results.to_json(true)?);
     }
 
-    if args.share && !args.csv && !args.json {
-        println!("{}", "Share functionality not yet implemented".yellow());
+    if let Some(ref share) = results.share {
+        if !args.csv && !args.json {
+            println!("{} {}", "Share results:".green().bold(), share);
+        }
+    }
+
+    Ok(())
+}
+
+/// Print the latency distribution measured against the best server
+fn print_latency_stats(stats: &LatencyStats) {
+    if stats.samples == 0 {
+        println!("{}", format!("All {} latency samples failed", stats.failures).yellow());
+        return;
+    }
+
+    println!(
+        "{} min {:.3} / median {:.3} / p90 {:.3} / max {:.3} ms, jitter {:.3} ms ({} of {} samples)",
+        "Latency:".green().bold(),
+        stats.min,
+        stats.median,
+        stats.p90,
+        stats.max,
+        stats.jitter,
+        stats.samples,
+        stats.samples + stats.failures
+    );
+}
+
+/// Print the bufferbloat grade with the idle and loaded latencies
+fn print_bufferbloat(bufferbloat: &Bufferbloat) {
+    let loaded = |direction: &str, stats: &Option<LatencyStats>| match stats {
+        Some(stats) if stats.samples > 0 => format!(
+            ", {} {:.3} ms (+{:.3})",
+            direction,
+            stats.median,
+            (stats.median - bufferbloat.idle).max(0.0)
+        ),
+        Some(_) => format!(", {} unreachable", direction),
+        None => String::new(),
+    };
+
+    println!(
+        "{} grade {} (idle {:.3} ms{}{})",
+        "Bufferbloat:".green().bold(),
+        bufferbloat.grade,
+        bufferbloat.idle,
+        loaded("download", &bufferbloat.download),
+        loaded("upload", &bufferbloat.upload)
+    );
+}
+
+/// Build the server filter from the --country, --sponsor, --name and distance options
+fn server_filter(args: &Args) -> Result<ServerFilter> {
+    let regex = |option: &str, pattern: &Option<String>| {
+        pattern
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .map_err(|e| SpeedtestError::CliError(format!("Invalid {} pattern: {}", option, e)))
+    };
+
+    Ok(ServerFilter {
+        countries: args.country.clone(),
+        sponsor: regex("--sponsor", &args.sponsor)?,
+        name: regex("--name", &args.name)?,
+        max_distance: args.max_distance,
+        min_distance: args.min_distance,
+    })
+}
+
+/// Build the best-server probing options from the --candidates and --ping-* options
+fn probe_options(args: &Args) -> ProbeOptions {
+    ProbeOptions {
+        candidates: args.candidates,
+        attempts: args.ping_attempts,
+        timeout: args.ping_timeout.map(Duration::from_secs_f64),
+        concurrency: args.ping_concurrency,
+    }
+}
+
+/// Interval of latency probes sent during transfers, when --bufferbloat is set
+fn loaded_latency_interval(args: &Args) -> Option<Duration> {
+    args.bufferbloat
+        .then(|| Duration::from_millis(args.bufferbloat_interval.max(1)))
+}
+
+/// Run ping, download and upload against `server` over a single address family
+fn run_family_test(
+    args: &Args,
+    http_options: &HttpOptions,
+    config: Config,
+    server: &Server,
+    family: IpFamily,
+) -> Result<SpeedtestResults> {
+    let mut speedtest = Speedtest::with_options(HttpOptions {
+        ip_family: Some(family),
+        ..http_options.clone()
+    })?;
+    speedtest.set_debug(args.debug);
+    speedtest.set_probe_options(probe_options(args));
+    speedtest.set_loaded_latency(loaded_latency_interval(args));
+    speedtest.set_config(config);
+
+    let best_server = speedtest.get_best_server(Some(std::slice::from_ref(server)))?.clone();
+    if args.latency_samples > 0 {
+        speedtest.measure_latency(args.latency_samples)?;
+    }
+    let mut results = speedtest.get_results()
+        .ok_or_else(|| SpeedtestError::Unknown("Failed to get results".to_string()))?;
+    results.ping = best_server.latency;
+
+    let threads = if args.single { Some(1) } else { None };
+    if !args.no_download {
+        let download_result = speedtest.download(|_, _, _, _| {}, threads)?;
+        results.set_download(&download_result);
+    }
+    if !args.no_upload {
+        let upload_result = speedtest.upload(|_, _, _, _| {}, threads, !args.no_pre_allocate)?;
+        results.set_upload(&upload_result);
+    }
+
+    Ok(results)
+}
+
+/// Run the full test once per address family and print the comparison
+fn compare_families(
+    args: &Args,
+    http_options: &HttpOptions,
+    speedtest: &Speedtest,
+    server: &Server,
+    quiet: bool,
+) -> Result<()> {
+    let config = speedtest.config()
+        .ok_or_else(|| SpeedtestError::ConfigRetrieval("Config not loaded".to_string()))?
+        .clone();
+
+    let run = |family: IpFamily| {
+        if !quiet {
+            println!("{}", format!("Testing over {}...", family).cyan());
+        }
+        let result = run_family_test(args, http_options, config.clone(), server, family);
+        FamilyResult::from_result(family, result)
+    };
+
+    let comparison = FamilyComparison {
+        server: server.clone(),
+        ipv4: run(IpFamily::Ipv4),
+        ipv6: run(IpFamily::Ipv6),
+    };
+
+    if args.json {
+        println!("{}", comparison.to_json(true)?);
+        return Ok(());
+    }
+
+    let divisor = if args.bytes { 8.0 } else { 1.0 };
+    let unit = if args.bytes { "byte" } else { "bit" };
+    let ping = |r: &FamilyResult| match r.results {
+        Some(ref results) => format!("{:.3} ms", results.ping),
+        None => "failed".to_string(),
+    };
+    let speed = |r: &FamilyResult, value: fn(&SpeedtestResults) -> f64| match r.results {
+        Some(ref results) => format!("{:.2} M{}/s", (value(results) / 1_000_000.0) / divisor, unit),
+        None => "failed".to_string(),
+    };
+
+    println!();
+    println!("{:<10} {:>18} {:>18}", "", "IPv4", "IPv6");
+    println!("{:<10} {:>18} {:>18}", "Ping", ping(&comparison.ipv4), ping(&comparison.ipv6));
+    if !args.no_download {
+        println!(
+            "{:<10} {:>18} {:>18}",
+            "Download",
+            speed(&comparison.ipv4, |r| r.download),
+            speed(&comparison.ipv6, |r| r.download)
+        );
+    }
+    if !args.no_upload {
+        println!(
+            "{:<10} {:>18} {:>18}",
+            "Upload",
+            speed(&comparison.ipv4, |r| r.upload),
+            speed(&comparison.ipv6, |r| r.upload)
+        );
+    }
+
+    for result in [&comparison.ipv4, &comparison.ipv6] {
+        if let Some(ref error) = result.error {
+            eprintln!("{} {} test failed: {}", "WARNING:".yellow().bold(), result.family, error);
+        }
     }
 
     Ok(())
@@ -4,10 +4,92 @@
 // Description: 
 // License: MIT
 
+use crate::error::SpeedtestError;
+use crate::http::HttpClient;
 use serde::{Deserialize, Serialize};
 // use std::collections::HashMap;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// speedtest.net configuration endpoint
+pub const CONFIG_URL: &str = "://www.speedtest.net/speedtest-config.php";
+
+/// speedtest.net server list endpoints, tried in order
+pub const SERVER_LIST_URLS: [&str; 4] = [
+    "://www.speedtest.net/speedtest-servers-static.php",
+    "http://c.speedtest.net/speedtest-servers-static.php",
+    "://www.speedtest.net/speedtest-servers.php",
+    "http://c.speedtest.net/speedtest-servers.php",
+];
+
+/// speedtest.net API endpoint used to submit results for sharing
+pub const SHARE_API_URL: &str = "://www.speedtest.net/api/api.php";
+
+/// Base URL of the generated share results images
+pub const SHARE_RESULT_URL: &str = "http://www.speedtest.net/result";
+
+/// Upload payload sizes in bytes, the config `ratio` drops the smallest ones
+pub const UPLOAD_SIZES: [usize; 7] = [32768, 65536, 131072, 262144, 524288, 1048576, 7340032];
+
+/// Download image sizes, each fetched as `random<size>x<size>.jpg`
+pub const DOWNLOAD_SIZES: [usize; 10] = [350, 500, 750, 1000, 1500, 2000, 2500, 3000, 3500, 4000];
+
+/// Config `ratio` used when the config omits it
+pub const DEFAULT_UPLOAD_RATIO: usize = 1;
+
+/// Config `maxchunkcount` used when the config omits it
+pub const DEFAULT_UPLOAD_MAX_CHUNKS: usize = 4;
+
+/// Config `threadsperurl` used when the config omits it
+pub const DEFAULT_DOWNLOAD_COUNT: usize = 4;
+
+/// Config upload `threads` used when the config omits it
+pub const DEFAULT_UPLOAD_THREADS: usize = 8;
+
+/// Config `threadcount` used when the config omits it, downloads use twice as many threads
+pub const DEFAULT_THREAD_COUNT: usize = 4;
+
+/// Config `testlength` in seconds used when the config omits it
+pub const DEFAULT_TEST_LENGTH: u64 = 10;
+
+/// Key mixed into the hash that signs shared results
+const SHARE_HASH_KEY: &str = "297aae72";
+
+/// URLs used for configuration, server lists and sharing
+///
+/// URLs starting with `://` use http or https depending on the `secure` setting.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Endpoints {
+    pub config_url: String,
+    pub server_urls: Vec<String>,
+    pub share_url: String,
+    /// Base URL of the share images, `<id>.png` is appended
+    pub share_result_url: String,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Self {
+            config_url: CONFIG_URL.to_string(),
+            server_urls: SERVER_LIST_URLS.iter().map(|url| url.to_string()).collect(),
+            share_url: SHARE_API_URL.to_string(),
+            share_result_url: SHARE_RESULT_URL.to_string(),
+        }
+    }
+}
+
+impl Endpoints {
+    /// Load endpoints from a JSON file, missing keys keep their defaults
+    pub fn from_file<P: AsRef<std::path::Path>>(path: P) -> crate::error::Result<Self> {
+        let path = path.as_ref();
+        let error = |e: &dyn std::fmt::Display| {
+            SpeedtestError::ConfigRetrieval(format!("{}: {}", path.display(), e))
+        };
+        let contents = std::fs::read_to_string(path).map_err(|e| error(&e))?;
+        serde_json::from_str(&contents).map_err(|e| error(&e))
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Client {
     #[serde(default)]
     pub ip: String,
@@ -37,13 +119,211 @@ pub struct Server {
     pub sponsor: String,
     pub name: String,
     pub country: String,
+    /// ISO country code
+    #[serde(default)]
+    pub cc: String,
     pub lat: f64,
     pub lon: f64,
     pub url: String,
     #[serde(default)]
     pub d: f64,
-    #[serde(default)]
+    /// Latency in ms, left out when it was never measured
+    #[serde(default, skip_serializing_if = "is_unmeasured")]
     pub latency: f64,
+    /// Whether the server answered latency probes, when it was pinged
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reachable: Option<bool>,
+    /// Server list URL or file the server was loaded from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+fn is_unmeasured(latency: &f64) -> bool {
+    *latency == 0.0
+}
+
+/// How candidate servers are probed for latency when picking the best one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProbeOptions {
+    /// Number of closest servers to probe
+    pub candidates: usize,
+    /// Latency requests per server, the median is used
+    pub attempts: usize,
+    /// Timeout of each latency request, the HTTP timeout when unset
+    pub timeout: Option<std::time::Duration>,
+    /// Servers probed at once, rayon's global pool when unset
+    pub concurrency: Option<usize>,
+}
+
+impl Default for ProbeOptions {
+    fn default() -> Self {
+        Self {
+            candidates: 5,
+            attempts: 3,
+            timeout: None,
+            concurrency: None,
+        }
+    }
+}
+
+/// Criteria a server must meet to be selected, all of them combined
+#[derive(Debug, Clone, Default)]
+pub struct ServerFilter {
+    /// Country names, matched case-insensitively
+    pub countries: Vec<String>,
+    pub sponsor: Option<regex::Regex>,
+    pub name: Option<regex::Regex>,
+    /// Maximum distance from the client in km
+    pub max_distance: Option<f64>,
+    /// Minimum distance from the client in km
+    pub min_distance: Option<f64>,
+}
+
+impl ServerFilter {
+    pub fn is_empty(&self) -> bool {
+        self.countries.is_empty()
+            && self.sponsor.is_none()
+            && self.name.is_none()
+            && self.max_distance.is_none()
+            && self.min_distance.is_none()
+    }
+
+    /// Check a server whose distance has already been computed
+    ///
+    /// Countries match the country name or the ISO country code.
+    pub fn matches(&self, server: &Server) -> bool {
+        (self.countries.is_empty()
+            || self.countries.iter().any(|c| {
+                c.eq_ignore_ascii_case(&server.country)
+                    || (!server.cc.is_empty() && c.eq_ignore_ascii_case(&server.cc))
+            }))
+            && self.sponsor.as_ref().is_none_or(|re| re.is_match(&server.sponsor))
+            && self.name.as_ref().is_none_or(|re| re.is_match(&server.name))
+            && self.max_distance.is_none_or(|max| server.d <= max)
+            && self.min_distance.is_none_or(|min| server.d >= min)
+    }
+}
+
+impl std::fmt::Display for ServerFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if !self.countries.is_empty() {
+            parts.push(format!("country {}", self.countries.join(" or ")));
+        }
+        if let Some(ref re) = self.sponsor {
+            parts.push(format!("sponsor matching /{}/", re));
+        }
+        if let Some(ref re) = self.name {
+            parts.push(format!("name matching /{}/", re));
+        }
+        if let Some(min) = self.min_distance {
+            parts.push(format!("at least {} km away", min));
+        }
+        if let Some(max) = self.max_distance {
+            parts.push(format!("at most {} km away", max));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// Format a server list as CSV with a header row
+///
+/// Latency is left empty for servers that were not pinged or were unreachable.
+pub fn servers_to_csv(servers: &[Server], delimiter: char) -> crate::error::Result<String> {
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(delimiter as u8)
+        .from_writer(vec![]);
+
+    wtr.write_record([
+        "Server ID",
+        "Sponsor",
+        "Server Name",
+        "Country",
+        "Distance",
+        "Latency",
+        "URL",
+        "Source",
+    ])?;
+
+    for server in servers {
+        let latency = match server.reachable {
+            Some(true) => format!("{:.3}", server.latency),
+            Some(false) => "unreachable".to_string(),
+            None => String::new(),
+        };
+        wtr.write_record(&[
+            server.id.to_string(),
+            server.sponsor.clone(),
+            server.name.clone(),
+            server.country.clone(),
+            format!("{:.2}", server.d),
+            latency,
+            server.url.clone(),
+            server.source.clone().unwrap_or_default(),
+        ])?;
+    }
+
+    let inner = wtr.into_inner().map_err(|e| {
+        crate::error::SpeedtestError::Csv(csv::Error::from(std::io::Error::other(
+            format!("Failed to finalize CSV: {}", e)
+        )))
+    })?;
+    Ok(String::from_utf8_lossy(&inner).to_string())
+}
+
+/// Distribution of latency samples in ms
+///
+/// Failed samples are counted in `failures` and left out of every statistic.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct LatencyStats {
+    pub samples: usize,
+    pub failures: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub median: f64,
+    pub p90: f64,
+    pub stddev: f64,
+    /// Mean absolute difference between consecutive samples
+    pub jitter: f64,
+}
+
+impl LatencyStats {
+    /// Summarize successful samples, in the order they were taken
+    pub fn from_samples(samples: &[f64], failures: usize) -> Self {
+        if samples.is_empty() {
+            return Self {
+                failures,
+                ..Self::default()
+            };
+        }
+
+        let n = samples.len() as f64;
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let mean = samples.iter().sum::<f64>() / n;
+        let variance = samples.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / n;
+        // Nearest-rank percentile
+        let p90_rank = ((0.9 * n).ceil() as usize).max(1);
+        let jitter = if samples.len() > 1 {
+            samples.windows(2).map(|w| (w[1] - w[0]).abs()).sum::<f64>() / (n - 1.0)
+        } else {
+            0.0
+        };
+
+        Self {
+            samples: samples.len(),
+            failures,
+            min: sorted[0],
+            max: sorted[sorted.len() - 1],
+            mean,
+            median: crate::utils::median(&sorted),
+            p90: sorted[p90_rank - 1],
+            stddev: variance.sqrt(),
+            jitter,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -57,18 +337,67 @@ pub struct Config {
     pub upload_max: usize,
 }
 
+impl Default for Config {
+    /// The settings speedtest.net hands out when its config omits them
+    fn default() -> Self {
+        let upload_sizes = Sizes::upload_for_ratio(DEFAULT_UPLOAD_RATIO);
+        let upload_count = Counts::upload_for(DEFAULT_UPLOAD_MAX_CHUNKS, upload_sizes.len());
+        let upload_max = upload_count * upload_sizes.len();
+
+        Self {
+            client: Client::default(),
+            ignore_servers: Vec::new(),
+            sizes: Sizes {
+                upload: upload_sizes,
+                download: DOWNLOAD_SIZES.to_vec(),
+            },
+            counts: Counts {
+                upload: upload_count,
+                download: DEFAULT_DOWNLOAD_COUNT,
+            },
+            threads: Threads {
+                upload: DEFAULT_UPLOAD_THREADS,
+                download: DEFAULT_THREAD_COUNT * 2,
+            },
+            length: Length {
+                upload: DEFAULT_TEST_LENGTH,
+                download: DEFAULT_TEST_LENGTH,
+            },
+            upload_max,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Sizes {
     pub upload: Vec<usize>,
     pub download: Vec<usize>,
 }
 
+impl Sizes {
+    /// Upload sizes for a config `ratio`, out of range ratios keep every size
+    pub fn upload_for_ratio(ratio: usize) -> Vec<usize> {
+        if ratio > 0 && ratio <= UPLOAD_SIZES.len() {
+            UPLOAD_SIZES[(ratio - 1)..].to_vec()
+        } else {
+            UPLOAD_SIZES.to_vec()
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Counts {
     pub upload: usize,
     pub download: usize,
 }
 
+impl Counts {
+    /// Times each upload size is sent so that `max_chunks` chunks are covered
+    pub fn upload_for(max_chunks: usize, size_count: usize) -> usize {
+        (max_chunks as f64 / size_count as f64).ceil() as usize
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Threads {
     pub upload: usize,
@@ -81,6 +410,177 @@ pub struct Length {
     pub download: u64,
 }
 
+/// IP address family used to reach the test server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl IpFamily {
+    pub fn of(ip: &std::net::IpAddr) -> Self {
+        match ip {
+            std::net::IpAddr::V4(_) => IpFamily::Ipv4,
+            std::net::IpAddr::V6(_) => IpFamily::Ipv6,
+        }
+    }
+
+    pub fn matches(&self, ip: &std::net::IpAddr) -> bool {
+        IpFamily::of(ip) == *self
+    }
+}
+
+impl std::fmt::Display for IpFamily {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IpFamily::Ipv4 => write!(f, "IPv4"),
+            IpFamily::Ipv6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+/// Live snapshot of a running download or upload test
+#[derive(Debug, Clone, Copy)]
+pub struct TransferProgress {
+    /// Bytes transferred since the test started
+    pub bytes: u64,
+    /// Throughput over the last reporting interval, in bits/s
+    pub rate: f64,
+    /// Time since the test started
+    pub elapsed: std::time::Duration,
+    /// Configured length of the test window
+    pub duration: std::time::Duration,
+}
+
+impl TransferProgress {
+    /// Average throughput since the test started, in bits/s
+    pub fn average(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            (self.bytes as f64 / secs) * 8.0
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Bytes transferred so far at one point of a download or upload test
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ThroughputSample {
+    /// Seconds since the test started
+    pub elapsed: f64,
+    /// Bytes transferred since the test started
+    pub bytes: u64,
+}
+
+/// Leading share of the test window ignored as TCP slow-start and thread ramp-up
+pub const WARMUP_FRACTION: f64 = 0.2;
+
+/// Share of the fastest and of the slowest intervals ignored as outliers
+pub const OUTLIER_FRACTION: f64 = 0.1;
+
+/// Speed in bits/s over the steady-state part of a throughput time series
+///
+/// Intervals starting inside the warm-up are dropped, then the fastest and
+/// slowest ones, and the rest are weighted by their length. `None` when no
+/// interval is left.
+pub fn steady_state_speed(samples: &[ThroughputSample]) -> Option<f64> {
+    let total = samples.last()?.elapsed;
+    let warmup = total * WARMUP_FRACTION;
+
+    let mut intervals: Vec<(f64, u64)> = samples
+        .windows(2)
+        .filter(|w| w[0].elapsed >= warmup && w[1].elapsed > w[0].elapsed)
+        .map(|w| (w[1].elapsed - w[0].elapsed, w[1].bytes.saturating_sub(w[0].bytes)))
+        .collect();
+    intervals.sort_by(|a, b| (a.1 as f64 / a.0).partial_cmp(&(b.1 as f64 / b.0)).unwrap());
+
+    let trim = (intervals.len() as f64 * OUTLIER_FRACTION).round() as usize;
+    let steady = &intervals[trim..intervals.len() - trim];
+    if steady.is_empty() {
+        return None;
+    }
+
+    let secs: f64 = steady.iter().map(|i| i.0).sum();
+    let bytes: u64 = steady.iter().map(|i| i.1).sum();
+    Some((bytes as f64 / secs) * 8.0)
+}
+
+/// Idle versus loaded latency and the resulting bufferbloat grade
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Bufferbloat {
+    /// Median latency in ms with the link idle
+    pub idle: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download: Option<LatencyStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload: Option<LatencyStats>,
+    /// A+ to F, from the worst latency increase of either direction
+    pub grade: String,
+}
+
+impl Bufferbloat {
+    pub fn new(idle: f64) -> Self {
+        Self {
+            idle,
+            download: None,
+            upload: None,
+            grade: bufferbloat_grade(0.0).to_string(),
+        }
+    }
+
+    /// Worst increase of the median latency over idle, in ms
+    ///
+    /// A direction where every probe failed counts as infinitely bloated.
+    pub fn increase(&self) -> f64 {
+        [self.download, self.upload]
+            .iter()
+            .flatten()
+            .map(|stats| match stats.samples {
+                0 if stats.failures > 0 => f64::INFINITY,
+                0 => 0.0,
+                _ => (stats.median - self.idle).max(0.0),
+            })
+            .fold(0.0, f64::max)
+    }
+
+    fn regrade(&mut self) {
+        self.grade = bufferbloat_grade(self.increase()).to_string();
+    }
+}
+
+/// Grade the latency added under load, in ms
+pub fn bufferbloat_grade(increase: f64) -> &'static str {
+    match increase {
+        i if i < 5.0 => "A+",
+        i if i < 30.0 => "A",
+        i if i < 60.0 => "B",
+        i if i < 200.0 => "C",
+        i if i < 400.0 => "D",
+        _ => "F",
+    }
+}
+
+/// Outcome of a completed download or upload test
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TransferResult {
+    /// Bytes transferred during the test
+    pub bytes: u64,
+    /// Length of the test in seconds
+    pub elapsed: f64,
+    /// Steady-state speed in bits/s, see `steady_state_speed`
+    pub speed: f64,
+    /// Speed in bits/s over the whole test, ramp-up included
+    pub average_speed: f64,
+    /// Bytes transferred over time, sampled at a fixed interval
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub samples: Vec<ThroughputSample>,
+    /// Latency measured while the transfer was running, when enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub loaded_latency: Option<LatencyStats>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SpeedtestResults {
     pub download: f64,
@@ -90,9 +590,31 @@ pub struct SpeedtestResults {
     pub timestamp: String,
     pub bytes_received: u64,
     pub bytes_sent: u64,
+    /// Length of the download test window, in seconds
+    #[serde(default)]
+    pub download_elapsed: f64,
+    /// Length of the upload test window, in seconds
+    #[serde(default)]
+    pub upload_elapsed: f64,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub share: Option<String>,
     pub client: Client,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip_family: Option<IpFamily>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency: Option<LatencyStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bufferbloat: Option<Bufferbloat>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub download_samples: Vec<ThroughputSample>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub upload_samples: Vec<ThroughputSample>,
 }
 
 impl SpeedtestResults {
@@ -105,11 +627,128 @@ impl SpeedtestResults {
             timestamp: chrono::Utc::now().to_rfc3339(),
             bytes_received: 0,
             bytes_sent: 0,
+            download_elapsed: 0.0,
+            upload_elapsed: 0.0,
             share: None,
             client,
+            interface: None,
+            local_address: None,
+            ip_family: None,
+            proxy: None,
+            latency: None,
+            bufferbloat: None,
+            download_samples: Vec::new(),
+            upload_samples: Vec::new(),
         }
     }
 
+    /// Record the outcome of the download test
+    pub fn set_download(&mut self, outcome: &TransferResult) {
+        self.download = outcome.speed;
+        self.bytes_received = outcome.bytes;
+        self.download_elapsed = outcome.elapsed;
+        self.download_samples = outcome.samples.clone();
+        if let Some(loaded) = outcome.loaded_latency {
+            let bufferbloat = self.bufferbloat();
+            bufferbloat.download = Some(loaded);
+            bufferbloat.regrade();
+        }
+    }
+
+    /// Record the outcome of the upload test
+    pub fn set_upload(&mut self, outcome: &TransferResult) {
+        self.upload = outcome.speed;
+        self.bytes_sent = outcome.bytes;
+        self.upload_elapsed = outcome.elapsed;
+        self.upload_samples = outcome.samples.clone();
+        if let Some(loaded) = outcome.loaded_latency {
+            let bufferbloat = self.bufferbloat();
+            bufferbloat.upload = Some(loaded);
+            bufferbloat.regrade();
+        }
+    }
+
+    /// Idle latency comes from the latency statistics when measured, else the ping
+    fn bufferbloat(&mut self) -> &mut Bufferbloat {
+        let idle = match self.latency {
+            Some(ref stats) if stats.samples > 0 => stats.median,
+            _ => self.ping,
+        };
+        self.bufferbloat.get_or_insert_with(|| Bufferbloat::new(idle))
+    }
+
+    /// Submit the results to speedtest.net and return the URL of the share image
+    pub fn share(&mut self, client: &HttpClient) -> crate::error::Result<&str> {
+        self.share_with_url(client, SHARE_API_URL, SHARE_RESULT_URL)
+    }
+
+    /// Submit the results to the given share API endpoint
+    ///
+    /// The share image URL is built from `result_url` and the returned result id.
+    pub fn share_with_url(
+        &mut self,
+        client: &HttpClient,
+        api_url: &str,
+        result_url: &str,
+    ) -> crate::error::Result<&str> {
+        if self.share.is_none() {
+            let response = client
+                .post_with_headers(
+                    api_url,
+                    self.share_payload().into_bytes(),
+                    &[("Referer", "http://c.speedtest.net/flash/speedtest.swf")],
+                )
+                .map_err(|e| SpeedtestError::ShareResultsConnectFailure(e.to_string()))?;
+
+            if !response.status().is_success() {
+                return Err(SpeedtestError::ShareResultsSubmitFailure(format!(
+                    "Could not submit results to speedtest.net (HTTP {})",
+                    response.status()
+                )));
+            }
+
+            let body = response
+                .text()
+                .map_err(|e| SpeedtestError::ShareResultsSubmitFailure(e.to_string()))?;
+            let result_id = parse_share_response(&body).ok_or_else(|| {
+                SpeedtestError::ShareResultsSubmitFailure(
+                    "Could not submit results to speedtest.net".to_string(),
+                )
+            })?;
+
+            self.share = Some(format!("{}/{}.png", result_url.trim_end_matches('/'), result_id));
+        }
+
+        Ok(self.share.as_deref().unwrap_or_default())
+    }
+
+    /// Build the signed form body expected by the speedtest.net API
+    fn share_payload(&self) -> String {
+        let ping = self.ping.round() as u64;
+        let download = (self.download / 1000.0).round() as u64;
+        let upload = (self.upload / 1000.0).round() as u64;
+        let hash = md5::compute(format!("{}-{}-{}-{}", ping, upload, download, SHARE_HASH_KEY));
+
+        [
+            format!("recommendedserverid={}", self.server.id),
+            format!("ping={}", ping),
+            "screenresolution=".to_string(),
+            "promo=".to_string(),
+            format!("download={}", download),
+            "screendpi=".to_string(),
+            format!("upload={}", upload),
+            "testmethod=http".to_string(),
+            format!("hash={:x}", hash),
+            "touchscreen=none".to_string(),
+            "startmode=pingselect".to_string(),
+            "accuracy=1".to_string(),
+            format!("bytesreceived={}", self.bytes_received),
+            format!("bytessent={}", self.bytes_sent),
+            format!("serverid={}", self.server.id),
+        ]
+        .join("&")
+    }
+
     pub fn to_csv(&self, delimiter: char) -> crate::error::Result<String> {
         let mut wtr = csv::WriterBuilder::new()
             .delimiter(delimiter as u8)
@@ -126,11 +765,12 @@ impl SpeedtestResults {
             format!("{:.2}", self.upload),
             self.share.clone().unwrap_or_default(),
             self.client.ip.clone(),
+            self.bytes_received.to_string(),
+            self.bytes_sent.to_string(),
         ])?;
 
         let inner = wtr.into_inner().map_err(|e| {
-            crate::error::SpeedtestError::Csv(csv::Error::from(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            crate::error::SpeedtestError::Csv(csv::Error::from(std::io::Error::other(
                 format!("Failed to finalize CSV: {}", e)
             )))
         })?;
@@ -142,7 +782,7 @@ impl SpeedtestResults {
             .delimiter(delimiter as u8)
             .from_writer(vec![]);
 
-        wtr.write_record(&[
+        wtr.write_record([
             "Server ID",
             "Sponsor",
             "Server Name",
@@ -153,11 +793,12 @@ impl SpeedtestResults {
             "Upload",
             "Share",
             "IP Address",
+            "Bytes Received",
+            "Bytes Sent",
         ])?;
 
         let inner = wtr.into_inner().map_err(|e| {
-            crate::error::SpeedtestError::Csv(csv::Error::from(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            crate::error::SpeedtestError::Csv(csv::Error::from(std::io::Error::other(
                 format!("Failed to finalize CSV: {}", e)
             )))
         })?;
@@ -171,4 +812,327 @@ impl SpeedtestResults {
             Ok(serde_json::to_string(self)?)
         }
     }
-}
\ No newline at end of file
+}
+
+/// Outcome of a test run restricted to one address family
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FamilyResult {
+    pub family: IpFamily,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub results: Option<SpeedtestResults>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl FamilyResult {
+    pub fn from_result(family: IpFamily, result: crate::error::Result<SpeedtestResults>) -> Self {
+        match result {
+            Ok(results) => Self {
+                family,
+                results: Some(results),
+                error: None,
+            },
+            Err(e) => Self {
+                family,
+                results: None,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+/// Side-by-side IPv4 and IPv6 results against the same server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FamilyComparison {
+    pub server: Server,
+    pub ipv4: FamilyResult,
+    pub ipv6: FamilyResult,
+}
+
+impl FamilyComparison {
+    pub fn to_json(&self, pretty: bool) -> crate::error::Result<String> {
+        if pretty {
+            Ok(serde_json::to_string_pretty(self)?)
+        } else {
+            Ok(serde_json::to_string(self)?)
+        }
+    }
+}
+
+/// Extract the single `resultid` value from a share API response
+fn parse_share_response(body: &str) -> Option<String> {
+    let ids: Vec<String> = url::form_urlencoded::parse(body.trim().as_bytes())
+        .filter(|(key, _)| key == "resultid")
+        .map(|(_, value)| value.into_owned())
+        .collect();
+
+    match ids.as_slice() {
+        [id] if !id.is_empty() => Some(id.clone()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_server() -> Server {
+        Server {
+            id: 1,
+            sponsor: String::new(),
+            name: String::new(),
+            country: String::new(),
+            cc: String::new(),
+            lat: 0.0,
+            lon: 0.0,
+            url: String::new(),
+            d: 0.0,
+            latency: 0.0,
+            reachable: None,
+            source: None,
+        }
+    }
+
+    #[test]
+    fn test_endpoints_from_file() {
+        let path = std::env::temp_dir().join(format!("speedtest-endpoints-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"config_url": "http://mirror.local/speedtest-config.php"}"#).unwrap();
+
+        let endpoints = Endpoints::from_file(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(endpoints.config_url, "http://mirror.local/speedtest-config.php");
+        assert_eq!(endpoints.server_urls, Endpoints::default().server_urls);
+        assert_eq!(endpoints.share_url, SHARE_API_URL);
+        assert_eq!(endpoints.share_result_url, SHARE_RESULT_URL);
+
+        // Parse and read failures name the file
+        std::fs::write(&path, "{not json").unwrap();
+        let invalid = Endpoints::from_file(&path);
+        let _ = std::fs::remove_file(&path);
+        let missing = Endpoints::from_file(&path);
+        for result in [invalid, missing] {
+            match result {
+                Err(SpeedtestError::ConfigRetrieval(msg)) => {
+                    assert!(msg.starts_with(&path.display().to_string()), "{}", msg)
+                }
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_share_response() {
+        assert_eq!(
+            parse_share_response("resultid=123456&date=1/1/2026"),
+            Some("123456".to_string())
+        );
+        assert_eq!(parse_share_response("error=bad hash"), None);
+        assert_eq!(parse_share_response("resultid=1&resultid=2"), None);
+    }
+
+    #[test]
+    fn test_transfer_results_in_output() {
+        let mut results = SpeedtestResults::new(Client::default(), test_server());
+        results.set_download(&TransferResult { bytes: 1_250_000, elapsed: 10.0, speed: 1_000_000.0, ..TransferResult::default() });
+        results.set_upload(&TransferResult { bytes: 625_000, elapsed: 10.0, speed: 500_000.0, ..TransferResult::default() });
+
+        let csv = results.to_csv(',').unwrap();
+        assert!(csv.trim_end().ends_with(",1250000,625000"));
+        assert_eq!(
+            SpeedtestResults::csv_header(',').unwrap().split(',').count(),
+            csv.split(',').count()
+        );
+        let json = results.to_json(false).unwrap();
+        assert!(json.contains("\"bytes_sent\":625000"));
+        assert!(json.contains("\"download_elapsed\":10.0"));
+        assert!(json.contains("\"upload_elapsed\":10.0"));
+    }
+
+    #[test]
+    fn test_family_comparison_json() {
+        let comparison = FamilyComparison {
+            server: test_server(),
+            ipv4: FamilyResult::from_result(
+                IpFamily::Ipv4,
+                Ok(SpeedtestResults::new(Client::default(), test_server())),
+            ),
+            ipv6: FamilyResult::from_result(
+                IpFamily::Ipv6,
+                Err(SpeedtestError::BestServerFailure("unreachable".to_string())),
+            ),
+        };
+
+        let json: serde_json::Value = serde_json::from_str(&comparison.to_json(false).unwrap()).unwrap();
+        assert_eq!(json["ipv4"]["family"], "ipv4");
+        assert!(json["ipv4"]["results"].is_object());
+        assert!(json["ipv6"]["results"].is_null());
+        assert!(json["ipv6"]["error"].as_str().unwrap().contains("unreachable"));
+    }
+
+    #[test]
+    fn test_share_with_url() {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 4096];
+            let _ = stream.read(&mut buf);
+            let body = "resultid=42";
+            let _ = write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+        });
+
+        let mut results = SpeedtestResults::new(Client::default(), test_server());
+        let http = HttpClient::new(5, false, None).unwrap();
+        let url = results
+            .share_with_url(
+                &http,
+                &format!("http://{}/api/api.php", addr),
+                &format!("http://{}/result/", addr),
+            )
+            .unwrap()
+            .to_string();
+        assert_eq!(url, format!("http://{}/result/42.png", addr));
+        assert!(results.to_json(false).unwrap().contains("42.png"));
+    }
+
+    #[test]
+    fn test_server_filter() {
+        let mut server = test_server();
+        server.sponsor = "Telkom Indonesia".to_string();
+        server.name = "Jakarta".to_string();
+        server.country = "Indonesia".to_string();
+        server.cc = "ID".to_string();
+        server.d = 12.0;
+
+        let mut filter = ServerFilter::default();
+        assert!(filter.is_empty());
+        assert!(filter.matches(&server));
+
+        filter.countries = vec!["id".to_string()];
+        assert!(filter.matches(&server));
+        filter.countries = vec!["US".to_string()];
+        assert!(!filter.matches(&server));
+
+        filter.countries = vec!["indonesia".to_string()];
+        filter.sponsor = Some(regex::Regex::new("^Telkom").unwrap());
+        filter.max_distance = Some(50.0);
+        assert!(filter.matches(&server));
+
+        filter.min_distance = Some(20.0);
+        assert!(!filter.matches(&server));
+        assert_eq!(
+            filter.to_string(),
+            "country indonesia, sponsor matching /^Telkom/, at least 20 km away, at most 50 km away"
+        );
+
+        filter.min_distance = None;
+        filter.name = Some(regex::Regex::new("Surabaya").unwrap());
+        assert!(!filter.matches(&server));
+    }
+
+    #[test]
+    fn test_servers_to_csv() {
+        let mut pinged = test_server();
+        pinged.sponsor = "Lab".to_string();
+        pinged.latency = 1.5;
+        pinged.reachable = Some(true);
+        let mut unreachable = test_server();
+        unreachable.id = 2;
+        unreachable.reachable = Some(false);
+        let mut unpinged = test_server();
+        unpinged.id = 3;
+
+        let servers = [pinged, unreachable, unpinged];
+        let csv = servers_to_csv(&servers, ';').unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "Server ID;Sponsor;Server Name;Country;Distance;Latency;URL;Source");
+        assert_eq!(lines[1], "1;Lab;;;0.00;1.500;;");
+        assert_eq!(lines[2], "2;;;;0.00;unreachable;;");
+        assert_eq!(lines[3], "3;;;;0.00;;;");
+
+        // JSON only carries what was measured
+        let json = serde_json::to_value(&servers).unwrap();
+        assert_eq!(json[0]["latency"], 1.5);
+        assert_eq!(json[0]["reachable"], true);
+        assert!(json[1].get("latency").is_none());
+        assert_eq!(json[1]["reachable"], false);
+        assert!(json[2].get("latency").is_none());
+        assert!(json[2].get("reachable").is_none());
+    }
+
+    #[test]
+    fn test_latency_stats() {
+        let stats = LatencyStats::from_samples(&[10.0, 12.0, 11.0, 15.0, 10.0], 2);
+        assert_eq!(stats.samples, 5);
+        assert_eq!(stats.failures, 2);
+        assert_eq!(stats.min, 10.0);
+        assert_eq!(stats.max, 15.0);
+        assert_eq!(stats.mean, 11.6);
+        assert_eq!(stats.median, 11.0);
+        assert_eq!(stats.p90, 15.0);
+        assert!((stats.stddev - 1.8547).abs() < 1e-3);
+        // |12-10| + |11-12| + |15-11| + |10-15| = 12 over 4 differences
+        assert_eq!(stats.jitter, 3.0);
+
+        let failed = LatencyStats::from_samples(&[], 3);
+        assert_eq!(failed.samples, 0);
+        assert_eq!(failed.failures, 3);
+    }
+
+    #[test]
+    fn test_bufferbloat() {
+        let loaded = |samples: &[f64], failures| TransferResult {
+            loaded_latency: Some(LatencyStats::from_samples(samples, failures)),
+            ..TransferResult::default()
+        };
+
+        let mut results = SpeedtestResults::new(Client::default(), test_server());
+        results.ping = 10.0;
+        results.set_download(&loaded(&[12.0, 13.0, 14.0], 0));
+        assert_eq!(results.bufferbloat.as_ref().unwrap().grade, "A+");
+
+        results.set_upload(&loaded(&[80.0, 90.0, 500.0], 1));
+        let bufferbloat = results.bufferbloat.as_ref().unwrap();
+        assert_eq!(bufferbloat.idle, 10.0);
+        assert_eq!(bufferbloat.increase(), 80.0);
+        assert_eq!(bufferbloat.grade, "C");
+
+        results.set_upload(&loaded(&[], 5));
+        assert_eq!(results.bufferbloat.as_ref().unwrap().grade, "F");
+
+        let mut unloaded = SpeedtestResults::new(Client::default(), test_server());
+        unloaded.set_download(&TransferResult::default());
+        assert!(unloaded.bufferbloat.is_none());
+    }
+
+    #[test]
+    fn test_steady_state_speed() {
+        let series = |points: &[(f64, u64)]| -> Vec<ThroughputSample> {
+            points
+                .iter()
+                .map(|&(elapsed, bytes)| ThroughputSample { elapsed, bytes })
+                .collect()
+        };
+
+        // Slow start in the first second, then a steady 1 MB/s with one stall
+        let mut points = vec![(0.0, 0), (1.0, 100_000)];
+        let mut bytes = 100_000;
+        for i in 2..=10 {
+            bytes += if i == 6 { 10_000 } else { 1_000_000 };
+            points.push((i as f64, bytes));
+        }
+        let speed = steady_state_speed(&series(&points)).unwrap();
+        assert_eq!(speed, 8_000_000.0);
+
+        assert_eq!(steady_state_speed(&[]), None);
+        assert_eq!(steady_state_speed(&series(&[(0.0, 0)])), None);
+    }
+}
@@ -0,0 +1,519 @@
+// File: src\server.rs
+// Author: Hadi Cahyadi <cumulus13@gmail.com>
+// Date: 2026-02-09
+// Description: Local server speaking the speedtest.net HTTP protocol
+// License: MIT
+
+use crate::error::Result;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+
+/// Size of the chunks used when streaming bodies
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Largest request body accepted for uploads
+const MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    /// Download bandwidth limit in bits/s, shared by all connections
+    pub download_rate: Option<u64>,
+    /// Upload bandwidth limit in bits/s, shared by all connections
+    pub upload_rate: Option<u64>,
+    /// Delay added before every response
+    pub latency: Duration,
+    /// Test length advertised in the configuration, in seconds
+    pub test_length: u64,
+    /// Base URL advertised in the server list, defaults to the request's Host header
+    pub public_url: Option<String>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            download_rate: None,
+            upload_rate: None,
+            latency: Duration::ZERO,
+            test_length: 10,
+            public_url: None,
+        }
+    }
+}
+
+/// Paces bytes so that all connections together stay under a rate
+struct Shaper {
+    bytes_per_sec: f64,
+    next: Mutex<Instant>,
+}
+
+impl Shaper {
+    fn new(bits_per_sec: Option<u64>) -> Option<Self> {
+        bits_per_sec.filter(|&rate| rate > 0).map(|rate| Self {
+            bytes_per_sec: rate as f64 / 8.0,
+            next: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Wait until `len` more bytes may be transferred
+    async fn reserve(&self, len: usize) {
+        let start = {
+            let mut next = self.next.lock().unwrap();
+            let start = (*next).max(Instant::now());
+            *next = start + Duration::from_secs_f64(len as f64 / self.bytes_per_sec);
+            start
+        };
+        tokio::time::sleep_until(start).await;
+    }
+}
+
+struct State {
+    options: ServerOptions,
+    download: Option<Shaper>,
+    upload: Option<Shaper>,
+}
+
+struct Request {
+    path: String,
+    headers: HashMap<String, String>,
+}
+
+/// Serve the speedtest protocol on `listener` until the task is dropped
+pub async fn serve(listener: TcpListener, options: ServerOptions) -> Result<()> {
+    let state = Arc::new(State {
+        download: Shaper::new(options.download_rate),
+        upload: Shaper::new(options.upload_rate),
+        options,
+    });
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let _ = handle_connection(stream, peer, state).await;
+        });
+    }
+}
+
+async fn handle_connection(stream: TcpStream, peer: SocketAddr, state: Arc<State>) -> std::io::Result<()> {
+    let _ = stream.set_nodelay(true);
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    loop {
+        let request = match read_request(&mut reader).await? {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+
+        let body_len = read_body(&mut reader, &request, state.upload.as_ref()).await?;
+
+        if !state.options.latency.is_zero() {
+            tokio::time::sleep(state.options.latency).await;
+        }
+
+        let keep_alive = !request
+            .headers
+            .get("connection")
+            .is_some_and(|v| v.eq_ignore_ascii_case("close"));
+
+        let path = request.path.split('?').next().unwrap_or_default();
+        let name = path.rsplit('/').next().unwrap_or_default();
+
+        if let Some(size) = parse_random_image(name) {
+            write_head(&mut writer, "200 OK", "image/jpeg", size).await?;
+            write_filler(&mut writer, size, state.download.as_ref()).await?;
+        } else if name.starts_with("upload.") {
+            let body = format!("size={}", body_len);
+            write_response(&mut writer, "200 OK", "text/plain", body.as_bytes()).await?;
+        } else if name == "latency.txt" {
+            write_response(&mut writer, "200 OK", "text/plain", b"test=test").await?;
+        } else if name == "speedtest-config.php" {
+            let body = config_xml(&state.options, &peer);
+            write_response(&mut writer, "200 OK", "text/xml", body.as_bytes()).await?;
+        } else if name == "speedtest-servers-static.php" || name == "speedtest-servers.php" {
+            let base_url = match state.options.public_url {
+                Some(ref url) => url.trim_end_matches('/').to_string(),
+                None => format!(
+                    "http://{}",
+                    request.headers.get("host").cloned().unwrap_or_default()
+                ),
+            };
+            let body = servers_xml(&base_url);
+            write_response(&mut writer, "200 OK", "text/xml", body.as_bytes()).await?;
+        } else if path == "/" || path == "/speedtest/" || name == "index.html" {
+            // Speedtest Mini landing page, advertises the upload handler
+            let body = "<html><script>var uploadExtension: \"php\";</script></html>";
+            write_response(&mut writer, "200 OK", "text/html", body.as_bytes()).await?;
+        } else {
+            write_response(&mut writer, "404 Not Found", "text/plain", b"Not Found").await?;
+        }
+
+        writer.flush().await?;
+
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+async fn read_request<R>(reader: &mut BufReader<R>) -> std::io::Result<Option<Request>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Ok(None);
+    }
+
+    let mut parts = line.split_whitespace().skip(1);
+    let path = parts.next().unwrap_or("/").to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+
+    Ok(Some(Request { path, headers }))
+}
+
+/// Read and discard the request body, returning its length
+async fn read_body<R>(
+    reader: &mut BufReader<R>,
+    request: &Request,
+    shaper: Option<&Shaper>,
+) -> std::io::Result<usize>
+where
+    R: AsyncRead + Unpin,
+{
+    let chunked = request
+        .headers
+        .get("transfer-encoding")
+        .is_some_and(|v| v.eq_ignore_ascii_case("chunked"));
+
+    if chunked {
+        let mut total = 0;
+        loop {
+            let mut size_line = String::new();
+            reader.read_line(&mut size_line).await?;
+            let size_hex = size_line.trim().split(';').next().unwrap_or_default();
+            let size = usize::from_str_radix(size_hex, 16)
+                .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "bad chunk size"))?;
+            if size == 0 {
+                // Skip trailers up to the terminating blank line
+                loop {
+                    let mut trailer = String::new();
+                    if reader.read_line(&mut trailer).await? == 0 || trailer.trim().is_empty() {
+                        break;
+                    }
+                }
+                return Ok(total);
+            }
+            discard(reader, size, shaper).await?;
+            total += size;
+            if total > MAX_BODY_SIZE {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "body too large"));
+            }
+            let mut crlf = String::new();
+            reader.read_line(&mut crlf).await?;
+        }
+    }
+
+    let length: usize = request
+        .headers
+        .get("content-length")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    if length > MAX_BODY_SIZE {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "body too large"));
+    }
+    discard(reader, length, shaper).await?;
+    Ok(length)
+}
+
+async fn discard<R>(reader: &mut R, mut remaining: usize, shaper: Option<&Shaper>) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    while remaining > 0 {
+        let want = remaining.min(buf.len());
+        if let Some(shaper) = shaper {
+            shaper.reserve(want).await;
+        }
+        let read = reader.read(&mut buf[..want]).await?;
+        if read == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        remaining -= read;
+    }
+    Ok(())
+}
+
+async fn write_head<W>(writer: &mut W, status: &str, content_type: &str, length: usize) -> std::io::Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\n\r\n",
+        status, content_type, length
+    );
+    writer.write_all(head.as_bytes()).await
+}
+
+async fn write_response<W>(writer: &mut W, status: &str, content_type: &str, body: &[u8]) -> std::io::Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    write_head(writer, status, content_type, body.len()).await?;
+    writer.write_all(body).await
+}
+
+async fn write_filler<W>(writer: &mut W, mut remaining: usize, shaper: Option<&Shaper>) -> std::io::Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    let chunk: Vec<u8> = (0..CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+    while remaining > 0 {
+        let len = remaining.min(chunk.len());
+        if let Some(shaper) = shaper {
+            shaper.reserve(len).await;
+        }
+        writer.write_all(&chunk[..len]).await?;
+        remaining -= len;
+    }
+    Ok(())
+}
+
+/// Size of `random{N}x{N}.jpg`, matching the ~2 bytes per pixel of the speedtest.net images
+fn parse_random_image(name: &str) -> Option<usize> {
+    let dims = name.strip_prefix("random")?.strip_suffix(".jpg")?;
+    let (width, height) = dims.split_once('x')?;
+    let width: usize = width.parse().ok()?;
+    let height: usize = height.parse().ok()?;
+    if width == 0 || height == 0 || width > 8000 || height > 8000 {
+        return None;
+    }
+    Some(width * height * 2)
+}
+
+fn config_xml(options: &ServerOptions, peer: &SocketAddr) -> String {
+    format!(
+        concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<settings>\n",
+            "<client ip=\"{ip}\" lat=\"0\" lon=\"0\" isp=\"Local Network\" isprating=\"3.7\" ",
+            "rating=\"0\" ispdlavg=\"0\" ispulavg=\"0\" loggedin=\"0\" country=\"LO\" />\n",
+            "<server-config threadcount=\"4\" ignoreids=\"\" notonmap=\"\" forcepingid=\"\" preferredserverid=\"\" />\n",
+            "<download testlength=\"{length}\" initialtest=\"250K\" mintestsize=\"250K\" threadsperurl=\"4\" />\n",
+            "<upload testlength=\"{length}\" ratio=\"5\" initialtest=\"0\" mintestsize=\"32K\" threads=\"4\" ",
+            "maxchunksize=\"512K\" maxchunkcount=\"50\" threadsperurl=\"4\" />\n",
+            "</settings>\n"
+        ),
+        ip = peer.ip(),
+        length = options.test_length
+    )
+}
+
+fn servers_xml(base_url: &str) -> String {
+    let host = base_url.split("://").nth(1).unwrap_or(base_url);
+    format!(
+        concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<settings>\n<servers>\n",
+            "<server url=\"{base}/speedtest/upload.php\" lat=\"0\" lon=\"0\" name=\"Local\" ",
+            "country=\"Local Network\" cc=\"LO\" sponsor=\"speedtest-server\" id=\"1\" host=\"{host}\" />\n",
+            "</servers>\n</settings>\n"
+        ),
+        base = base_url,
+        host = host
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::HttpClient;
+    use crate::models::{Config, Counts, Endpoints, Length, Sizes, Threads};
+    use crate::Speedtest;
+
+    fn start_server(options: ServerOptions) -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async move {
+                let listener = TcpListener::from_std(listener).unwrap();
+                let _ = serve(listener, options).await;
+            });
+        });
+        addr
+    }
+
+    #[test]
+    fn test_parse_random_image() {
+        assert_eq!(parse_random_image("random350x350.jpg"), Some(245_000));
+        assert_eq!(parse_random_image("random350x350.png"), None);
+        assert_eq!(parse_random_image("latency.txt"), None);
+    }
+
+    #[test]
+    fn test_protocol_endpoints() {
+        let addr = start_server(ServerOptions::default());
+        let client = HttpClient::new(5, false, None).unwrap();
+
+        let latency = client.get_text(&format!("http://{}/speedtest/latency.txt", addr)).unwrap();
+        assert_eq!(latency, "test=test");
+
+        let image = client.get_bytes(&format!("http://{}/speedtest/random500x500.jpg", addr)).unwrap();
+        assert_eq!(image.len(), 500_000);
+
+        let upload = client
+            .post(&format!("http://{}/speedtest/upload.php", addr), vec![b'x'; 1000])
+            .unwrap()
+            .text()
+            .unwrap();
+        assert_eq!(upload, "size=1000");
+
+        let servers = client.get_text(&format!("http://{}/speedtest-servers.php", addr)).unwrap();
+        assert!(servers.contains(&format!("http://{}/speedtest/upload.php", addr)));
+    }
+
+    #[test]
+    fn test_get_discard_streams_in_chunks() {
+        let addr = start_server(ServerOptions::default());
+        let client = HttpClient::new(5, false, None).unwrap();
+        let url = format!("http://{}/speedtest/random500x500.jpg", addr);
+
+        let mut buf = vec![0u8; 4096];
+        let mut chunks = Vec::new();
+        let total = client.get_discard(&url, &mut buf, Duration::from_secs(5), |n| {
+            chunks.push(n);
+            true
+        }).unwrap();
+        assert_eq!(total, 500_000);
+        assert_eq!(chunks.iter().sum::<usize>(), 500_000);
+        assert!(chunks.iter().all(|&n| n > 0 && n <= 4096));
+
+        // Returning false stops reading the rest of the body
+        let mut seen = 0;
+        let total = client.get_discard(&url, &mut buf, Duration::from_secs(5), |n| {
+            seen += n;
+            seen < 100_000
+        }).unwrap();
+        assert!((100_000..500_000).contains(&total));
+    }
+
+    #[test]
+    fn test_end_to_end_with_shaping() {
+        let addr = start_server(ServerOptions {
+            download_rate: Some(8_000_000),
+            latency: Duration::from_millis(20),
+            ..ServerOptions::default()
+        });
+
+        let mut speedtest = Speedtest::new(5, false, None).unwrap();
+        speedtest.set_config(Config {
+            client: Default::default(),
+            ignore_servers: Vec::new(),
+            sizes: Sizes {
+                upload: vec![32768],
+                download: vec![350],
+            },
+            counts: Counts { upload: 1, download: 1 },
+            threads: Threads { upload: 2, download: 2 },
+            length: Length { upload: 1, download: 2 },
+            upload_max: 1,
+        });
+        let servers = speedtest.set_mini_server(&format!("http://{}/", addr)).unwrap().to_vec();
+        let best = speedtest.get_best_server(Some(&servers)).unwrap();
+        assert!(best.latency >= 20.0);
+
+        let download = speedtest.download(|_, _, _, _| {}, None).unwrap();
+        assert!(download.bytes > 0);
+        // 8 Mbit/s shaping, allow for bursts at the window edges
+        assert!(download.speed < 12_000_000.0, "speed {}", download.speed);
+
+        let upload = speedtest.upload(|_, _, _, _| {}, None, true).unwrap();
+        assert!(upload.bytes > 0);
+        assert!(upload.loaded_latency.is_none());
+
+        speedtest.set_loaded_latency(Some(Duration::from_millis(100)));
+        let download = speedtest.download(|_, _, _, _| {}, None).unwrap();
+        let loaded = download.loaded_latency.unwrap();
+        assert!(loaded.samples >= 5, "samples {}", loaded.samples);
+        assert!(loaded.min >= 20.0);
+    }
+
+    #[test]
+    fn test_window_close_aborts_transfers() {
+        let addr = start_server(ServerOptions {
+            download_rate: Some(8_000_000),
+            upload_rate: Some(8_000_000),
+            ..ServerOptions::default()
+        });
+
+        // A single 32 MB image or 7 MB upload takes far longer than the window
+        let mut speedtest = Speedtest::new(30, false, None).unwrap();
+        speedtest.set_config(Config {
+            client: Default::default(),
+            ignore_servers: Vec::new(),
+            sizes: Sizes {
+                upload: vec![7340032],
+                download: vec![4000],
+            },
+            counts: Counts { upload: 1, download: 1 },
+            threads: Threads { upload: 1, download: 1 },
+            length: Length { upload: 1, download: 1 },
+            upload_max: 1,
+        });
+        speedtest.set_mini_server(&format!("http://{}/", addr)).unwrap();
+
+        let start = Instant::now();
+        let download = speedtest.download(|_, _, _, _| {}, None).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(3), "took {:?}", start.elapsed());
+        assert!(download.elapsed < 1.5);
+        assert!(download.bytes > 0 && download.bytes < 4_000_000, "bytes {}", download.bytes);
+
+        let start = Instant::now();
+        let upload = speedtest.upload(|_, _, _, _| {}, None, true).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(3), "took {:?}", start.elapsed());
+        assert!(upload.bytes > 0 && upload.bytes < 7_340_032, "bytes {}", upload.bytes);
+    }
+
+    #[test]
+    fn test_config_and_servers_from_endpoints() {
+        let addr = start_server(ServerOptions::default());
+
+        let mut speedtest = Speedtest::new(5, false, None).unwrap();
+        speedtest.set_endpoints(Endpoints {
+            config_url: format!("http://{}/speedtest-config.php", addr),
+            server_urls: vec![format!("http://{}/speedtest-servers.php", addr)],
+            ..Endpoints::default()
+        });
+
+        let config = speedtest.get_config().unwrap();
+        assert_eq!(config.client.ip, "127.0.0.1");
+
+        let servers = speedtest.get_servers(None, None).unwrap();
+        assert_eq!(servers.len(), 1);
+
+        let best = speedtest.get_best_server(None).unwrap();
+        assert_eq!(best.url, format!("http://{}/speedtest/upload.php", addr));
+    }
+}
@@ -4,12 +4,14 @@
 // Description: Command-line interface for testing internet bandwidth using speedtest.net
 // License: MIT
 
+use crate::cache::{cache_key, Cache};
 use crate::error::{Result, SpeedtestError};
-use crate::http::HttpClient;
+use crate::http::{HttpClient, HttpOptions};
 use crate::models::*;
-use crate::utils::distance;
+use crate::utils::{distance, median};
 use rayon::prelude::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
@@ -21,12 +23,30 @@ pub struct Speedtest {
     closest: Vec<Server>,
     best: Option<Server>,
     lat_lon: (f64, f64),
+    location: Option<(f64, f64)>,
+    ip_family: Option<IpFamily>,
+    endpoints: Endpoints,
+    cache: Option<Cache>,
+    merge_servers: bool,
+    filter: ServerFilter,
+    probe: ProbeOptions,
+    latency: Option<LatencyStats>,
+    loaded_latency: Option<Duration>,
     debug: bool,
 }
 
 impl Speedtest {
     pub fn new(timeout: u64, secure: bool, source_address: Option<String>) -> Result<Self> {
-        let client = HttpClient::new(timeout, secure, source_address)?;
+        Self::with_options(HttpOptions {
+            timeout,
+            secure,
+            source_address,
+            ..HttpOptions::default()
+        })
+    }
+
+    pub fn with_options(options: HttpOptions) -> Result<Self> {
+        let client = HttpClient::with_options(options)?;
 
         Ok(Self {
             config: None,
@@ -35,6 +55,15 @@ impl Speedtest {
             closest: Vec::new(),
             best: None,
             lat_lon: (0.0, 0.0),
+            location: None,
+            ip_family: None,
+            endpoints: Endpoints::default(),
+            cache: None,
+            merge_servers: false,
+            filter: ServerFilter::default(),
+            probe: ProbeOptions::default(),
+            latency: None,
+            loaded_latency: None,
             debug: false,
         })
     }
@@ -43,169 +72,210 @@ impl Speedtest {
         self.debug = debug;
     }
 
-    pub fn get_config(&mut self) -> Result<&Config> {
-        if self.config.is_some() {
-            return Ok(self.config.as_ref().unwrap());
-        }
+    pub fn endpoints(&self) -> &Endpoints {
+        &self.endpoints
+    }
 
-        let xml = self
-            .client
-            .get_text("://www.speedtest.net/speedtest-config.php")?;
-
-        // Parse XML manually to extract attributes
-        use quick_xml::events::Event;
-        use quick_xml::Reader;
-        use std::collections::HashMap;
-
-        let mut reader = Reader::from_str(&xml);
-        reader.trim_text(true);
-
-        let mut client_attrs: HashMap<String, String> = HashMap::new();
-        let mut server_config_attrs: HashMap<String, String> = HashMap::new();
-        let mut download_attrs: HashMap<String, String> = HashMap::new();
-        let mut upload_attrs: HashMap<String, String> = HashMap::new();
-
-        let mut buf = Vec::new();
-        loop {
-            match reader.read_event_into(&mut buf) {
-                Ok(Event::Empty(e)) => {
-                    let name = String::from_utf8_lossy(e.name().as_ref()).to_string();
-                    
-                    let attrs: HashMap<String, String> = e
-                        .attributes()
-                        .filter_map(|a| a.ok())
-                        .map(|a| {
-                            (
-                                String::from_utf8_lossy(a.key.as_ref()).to_string(),
-                                String::from_utf8_lossy(&a.value).to_string(),
-                            )
-                        })
-                        .collect();
+    /// Use a different configuration and server list source than speedtest.net
+    pub fn set_endpoints(&mut self, endpoints: Endpoints) {
+        self.endpoints = endpoints;
+    }
 
-                    match name.as_str() {
-                        "client" => client_attrs = attrs,
-                        "server-config" => server_config_attrs = attrs,
-                        "download" => download_attrs = attrs,
-                        "upload" => upload_attrs = attrs,
-                        _ => {}
-                    }
-                }
-                Ok(Event::Eof) => break,
-                Err(e) => {
-                    return Err(SpeedtestError::ConfigRetrieval(format!(
-                        "XML parse error at position {}: {:?}",
-                        reader.buffer_position(),
-                        e
-                    )))
-                }
-                _ => {}
-            }
-            buf.clear();
-        }
-
-        // Build Client from attributes
-        let client = Client {
-            ip: client_attrs.get("ip").cloned().unwrap_or_default(),
-            lat: client_attrs.get("lat").cloned().unwrap_or_default(),
-            lon: client_attrs.get("lon").cloned().unwrap_or_default(),
-            isp: client_attrs.get("isp").cloned().unwrap_or_default(),
-            country: client_attrs.get("country").cloned().unwrap_or_default(),
-            isprating: client_attrs.get("isprating").cloned().unwrap_or_default(),
-            rating: client_attrs.get("rating").cloned().unwrap_or_default(),
-            ispdlavg: client_attrs.get("ispdlavg").cloned().unwrap_or_default(),
-            ispulavg: client_attrs.get("ispulavg").cloned().unwrap_or_default(),
-            loggedin: client_attrs.get("loggedin").cloned().unwrap_or_default(),
-        };
+    /// Read the configuration and server list from an on-disk cache when fresh
+    ///
+    /// A stale entry is still used when fetching from the network fails.
+    pub fn set_cache(&mut self, cache: Option<Cache>) {
+        self.cache = cache;
+    }
 
-        // Validate client data
-        if client.ip.is_empty() {
-            return Err(SpeedtestError::ConfigRetrieval(
-                "Client IP address not provided by server".to_string()
-            ));
-        }
+    /// Fetch every configured server list concurrently and merge them by id
+    /// instead of stopping at the first list with matching servers
+    pub fn set_merge_servers(&mut self, merge: bool) {
+        self.merge_servers = merge;
+    }
 
-        let ignore_servers: Vec<u32> = server_config_attrs
-            .get("ignoreids")
-            .unwrap_or(&String::new())
-            .split(',')
-            .filter_map(|s| s.trim().parse().ok())
-            .collect();
+    /// Only select servers matching the filter, in addition to the id filters
+    pub fn set_server_filter(&mut self, filter: ServerFilter) {
+        self.filter = filter;
+    }
 
-        let ratio: usize = upload_attrs
-            .get("ratio")
-            .and_then(|s| s.parse().ok())
-            .unwrap_or(1);
-        
-        let upload_max: usize = upload_attrs
-            .get("maxchunkcount")
-            .and_then(|s| s.parse().ok())
-            .unwrap_or(4);
-
-        let up_sizes = vec![32768, 65536, 131072, 262144, 524288, 1048576, 7340032];
-        let upload_sizes: Vec<usize> = if ratio > 0 && ratio <= up_sizes.len() {
-            up_sizes[(ratio - 1)..].to_vec()
-        } else {
-            up_sizes
+    /// Change how many servers are probed for latency and how
+    pub fn set_probe_options(&mut self, probe: ProbeOptions) {
+        self.probe = probe;
+    }
+
+    /// Probe latency every `interval` during download and upload to measure
+    /// latency under load, reported in `TransferResult::loaded_latency`
+    pub fn set_loaded_latency(&mut self, interval: Option<Duration>) {
+        self.loaded_latency = interval;
+    }
+
+    pub fn config(&self) -> Option<&Config> {
+        self.config.as_ref()
+    }
+
+    /// Use an already retrieved configuration instead of fetching it
+    pub fn set_config(&mut self, config: Config) {
+        if self.location.is_none() {
+            self.lat_lon = (
+                config.client.lat.parse().unwrap_or(0.0),
+                config.client.lon.parse().unwrap_or(0.0),
+            );
+        }
+        self.config = Some(config);
+    }
+
+    /// Use the default configuration instead of fetching it from speedtest.net
+    ///
+    /// The client IP is the local address the tests will be sent from.
+    pub fn use_local_config(&mut self) -> Result<&Config> {
+        let ip = match self.client.local_address() {
+            Some(ip) => ip,
+            None => match self.client.options().ip_family {
+                Some(IpFamily::Ipv6) => local_ip_address::local_ipv6(),
+                _ => local_ip_address::local_ip(),
+            }
+            .map_err(|e| {
+                SpeedtestError::ConfigRetrieval(format!("Could not determine local IP address: {}", e))
+            })?,
         };
 
-        let size_count = upload_sizes.len();
-        let upload_count = (upload_max as f64 / size_count as f64).ceil() as usize;
+        if self.debug {
+            eprintln!("Using local configuration for {}", ip);
+        }
+
+        self.set_config(Config {
+            client: Client {
+                ip: ip.to_string(),
+                ..Client::default()
+            },
+            ..Config::default()
+        });
+        Ok(self.config.as_ref().unwrap())
+    }
 
-        let lat: f64 = client.lat.parse()
-            .unwrap_or_else(|_| {
-                eprintln!("Warning: Could not parse latitude '{}', using default 0.0", client.lat);
-                0.0
-            });
-        let lon: f64 = client.lon.parse()
-            .unwrap_or_else(|_| {
-                eprintln!("Warning: Could not parse longitude '{}', using default 0.0", client.lon);
-                0.0
-            });
+    pub fn lat_lon(&self) -> (f64, f64) {
+        self.lat_lon
+    }
+
+    /// Use a fixed client location instead of the one geolocated by the config
+    ///
+    /// Distances of servers that are already loaded are recomputed.
+    pub fn set_location(&mut self, lat: f64, lon: f64) -> Result<()> {
+        if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+            return Err(SpeedtestError::InvalidLocation(format!("{}, {}", lat, lon)));
+        }
 
+        self.location = Some((lat, lon));
         self.lat_lon = (lat, lon);
 
-        let config = Config {
-            client,
-            ignore_servers,
-            sizes: Sizes {
-                upload: upload_sizes,
-                download: vec![350, 500, 750, 1000, 1500, 2000, 2500, 3000, 3500, 4000],
-            },
-            counts: Counts {
-                upload: upload_count,
-                download: download_attrs
-                    .get("threadsperurl")
-                    .and_then(|s| s.parse().ok())
-                    .unwrap_or(4),
-            },
-            threads: Threads {
-                upload: upload_attrs
-                    .get("threads")
-                    .and_then(|s| s.parse().ok())
-                    .unwrap_or(8),
-                download: server_config_attrs
-                    .get("threadcount")
-                    .and_then(|s| s.parse::<usize>().ok())
-                    .unwrap_or(4)
-                    * 2,
-            },
-            length: Length {
-                upload: upload_attrs
-                    .get("testlength")
-                    .and_then(|s| s.parse().ok())
-                    .unwrap_or(10),
-                download: download_attrs
-                    .get("testlength")
-                    .and_then(|s| s.parse().ok())
-                    .unwrap_or(10),
+        for server in self.servers.values_mut().flatten() {
+            server.d = distance(lat, lon, server.lat, server.lon);
+        }
+        for server in &mut self.closest {
+            server.d = distance(lat, lon, server.lat, server.lon);
+        }
+        self.closest.sort_by(|a, b| a.d.total_cmp(&b.d));
+        Ok(())
+    }
+
+    pub fn client(&self) -> &HttpClient {
+        &self.client
+    }
+
+    pub fn get_config(&mut self) -> Result<&Config> {
+        if let Some(ref config) = self.config {
+            return Ok(config);
+        }
+
+        if self.debug {
+            if let Some(proxy) = self.client.proxy_for(&self.endpoints.config_url) {
+                eprintln!("Using proxy for configuration: {}", proxy);
+            }
+        }
+
+        let key = self.config_cache_key();
+        let cached = self.cache.as_ref().and_then(|cache| cache.get::<Config>(&key));
+
+        let config = match cached {
+            Some(config) => {
+                if self.debug {
+                    eprintln!("Using cached configuration");
+                }
+                config
+            }
+            None => match self.fetch_config() {
+                Ok(config) => {
+                    self.store_cache(&key, &config);
+                    config
+                }
+                Err(e) => match self.cache.as_ref().and_then(|cache| cache.get_stale::<Config>(&key)) {
+                    Some(config) => {
+                        eprintln!("Warning: {}, using cached configuration", e);
+                        config
+                    }
+                    None => return Err(e),
+                },
             },
-            upload_max: upload_count * size_count,
         };
 
+        if self.location.is_none() {
+            let lat: f64 = config.client.lat.parse()
+                .unwrap_or_else(|_| {
+                    eprintln!("Warning: Could not parse latitude '{}', using default 0.0", config.client.lat);
+                    0.0
+                });
+            let lon: f64 = config.client.lon.parse()
+                .unwrap_or_else(|_| {
+                    eprintln!("Warning: Could not parse longitude '{}', using default 0.0", config.client.lon);
+                    0.0
+                });
+
+            self.lat_lon = (lat, lon);
+        }
+
         self.config = Some(config);
         Ok(self.config.as_ref().unwrap())
     }
 
+    /// The configuration depends on how the client reaches the config endpoint
+    fn config_cache_key(&self) -> String {
+        let options = self.client.options();
+        cache_key(
+            "config",
+            &[
+                &self.endpoints.config_url,
+                options.source_address.as_deref().unwrap_or_default(),
+                options.interface.as_deref().unwrap_or_default(),
+                &options.ip_family.map(|f| f.to_string()).unwrap_or_default(),
+                &self.client.proxy_for(&self.endpoints.config_url).unwrap_or_default(),
+            ],
+        )
+    }
+
+    fn servers_cache_key(&self) -> String {
+        let mut parts: Vec<&str> = self.endpoints.server_urls.iter().map(String::as_str).collect();
+        if self.merge_servers {
+            parts.push("merge");
+        }
+        cache_key("servers", &parts)
+    }
+
+    fn store_cache<T: serde::Serialize>(&self, key: &str, data: &T) {
+        if let Some(ref cache) = self.cache {
+            if let Err(e) = cache.put(key, data) {
+                if self.debug {
+                    eprintln!("Failed to write cache {}: {}", cache.dir().display(), e);
+                }
+            }
+        }
+    }
+
+    fn fetch_config(&self) -> Result<Config> {
+        let xml = self.client.get_text(&self.endpoints.config_url)?;
+        parse_config_xml(&xml)
+    }
+
     pub fn get_servers(
         &mut self,
         server_ids: Option<&[u32]>,
@@ -213,40 +283,109 @@ impl Speedtest {
     ) -> Result<&HashMap<u32, Vec<Server>>> {
         self.servers.clear();
 
-        let urls = vec![
-            "://www.speedtest.net/speedtest-servers-static.php",
-            "http://c.speedtest.net/speedtest-servers-static.php",
-            "://www.speedtest.net/speedtest-servers.php",
-            "http://c.speedtest.net/speedtest-servers.php",
-        ];
-
-        for url in urls {
+        let key = self.servers_cache_key();
+        if let Some(servers) = self.cache.as_ref().and_then(|cache| cache.get::<Vec<Server>>(&key)) {
             if self.debug {
-                eprintln!("Trying to fetch servers from: {}", url);
+                eprintln!("Using cached server list ({} servers)", servers.len());
             }
-            match self.fetch_servers(url, server_ids, exclude) {
-                Ok(_) if !self.servers.is_empty() => {
-                    if self.debug {
-                        eprintln!("Found {} unique servers", self.servers.len());
-                    }
-                    break;
+            self.add_servers(servers, server_ids, exclude)?;
+
+            // The cached list may not be the one holding the requested servers
+            if self.servers.is_empty() && self.debug {
+                eprintln!("No matching servers in the cached list, fetching");
+            }
+        }
+
+        if self.servers.is_empty() {
+            let urls = self.endpoints.server_urls.clone();
+            let mut fetched = false;
+            let mut last_error = None;
+
+            if self.merge_servers {
+                if self.debug {
+                    eprintln!("Fetching servers from {} lists", urls.len());
                 }
-                Ok(_) => {
-                    if self.debug {
-                        eprintln!("No servers found from this URL");
+
+                let lists: Vec<(&String, Result<Vec<Server>>)> = urls
+                    .par_iter()
+                    .map(|url| (url, self.fetch_servers(url)))
+                    .collect();
+
+                // Keep the first occurrence of each id, in the configured URL order
+                let mut seen = HashSet::new();
+                let mut merged = Vec::new();
+                for (url, result) in lists {
+                    match result {
+                        Ok(servers) => {
+                            if self.debug {
+                                eprintln!("Found {} servers from: {}", servers.len(), url);
+                            }
+                            fetched = true;
+                            merged.extend(servers.into_iter().filter(|s| seen.insert(s.id)));
+                        }
+                        Err(e) => {
+                            if self.debug {
+                                eprintln!("Failed to fetch {}: {}", url, e);
+                            }
+                            last_error = Some(e);
+                        }
                     }
                 }
-                Err(e) => {
+
+                if fetched {
+                    self.store_cache(&key, &merged);
+                    self.add_servers(merged, server_ids, exclude)?;
+                }
+            } else {
+                let mut cached = false;
+                for url in &urls {
                     if self.debug {
-                        eprintln!("Failed to fetch: {}", e);
+                        eprintln!("Trying to fetch servers from: {}", url);
                     }
-                    continue;
+                    match self.fetch_servers(url) {
+                        Ok(servers) => {
+                            // Cache the list an unfiltered run would use, whatever
+                            // list ends up holding the requested servers
+                            if !cached && !servers.is_empty() {
+                                self.store_cache(&key, &servers);
+                                cached = true;
+                            }
+                            fetched = true;
+                            self.add_servers(servers, server_ids, exclude)?;
+                            if !self.servers.is_empty() {
+                                if self.debug {
+                                    eprintln!("Found {} unique servers", self.servers.len());
+                                }
+                                break;
+                            }
+                            if self.debug {
+                                eprintln!("No servers found from this URL");
+                            }
+                        }
+                        Err(e) => {
+                            if self.debug {
+                                eprintln!("Failed to fetch: {}", e);
+                            }
+                            last_error = Some(e);
+                            continue;
+                        }
+                    }
+                }
+            }
+
+            // Every source failed, fall back to a cached list of any age
+            if let (false, Some(e)) = (fetched, last_error) {
+                if let Some(servers) = self.cache.as_ref().and_then(|cache| cache.get_stale::<Vec<Server>>(&key)) {
+                    eprintln!("Warning: {}, using cached server list", e);
+                    self.add_servers(servers, server_ids, exclude)?;
                 }
             }
         }
 
-        if (server_ids.is_some() || exclude.is_some()) && self.servers.is_empty() {
-            return Err(SpeedtestError::NoMatchedServers);
+        if (server_ids.is_some() || exclude.is_some() || !self.filter.is_empty())
+            && self.servers.is_empty()
+        {
+            return Err(self.no_matched_servers(server_ids, exclude));
         }
 
         if self.debug {
@@ -255,123 +394,145 @@ impl Speedtest {
         Ok(&self.servers)
     }
 
-    fn fetch_servers(
+    fn fetch_servers(&self, url: &str) -> Result<Vec<Server>> {
+        let xml = self.client.get_text(url)?;
+        let mut servers = parse_servers_xml(&xml)?;
+        for server in &mut servers {
+            server.source = Some(url.to_string());
+        }
+        Ok(servers)
+    }
+
+    /// Load the server list from a speedtest.net XML file or a JSON array of servers
+    pub fn load_servers_file<P: AsRef<Path>>(
         &mut self,
-        url: &str,
+        path: P,
         server_ids: Option<&[u32]>,
         exclude: Option<&[u32]>,
-    ) -> Result<()> {
-        let xml = self.client.get_text(url)?;
+    ) -> Result<&HashMap<u32, Vec<Server>>> {
+        let path = path.as_ref();
+        self.servers.clear();
 
-        // Parse XML manually to extract server attributes
-        use quick_xml::events::Event;
-        use quick_xml::Reader;
+        if self.debug {
+            eprintln!("Loading servers from file: {}", path.display());
+        }
 
-        let mut reader = Reader::from_str(&xml);
-        reader.trim_text(true);
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            SpeedtestError::ServersRetrieval(format!("{}: {}", path.display(), e))
+        })?;
 
-        let config = self.config.as_ref()
-            .ok_or_else(|| SpeedtestError::ConfigRetrieval("Config not loaded".to_string()))?;
+        let mut servers = if contents.trim_start().starts_with('[') {
+            serde_json::from_str::<Vec<Server>>(&contents).map_err(|e| {
+                SpeedtestError::ServersRetrieval(format!("{}: {}", path.display(), e))
+            })?
+        } else {
+            parse_servers_xml(&contents)?
+        };
 
-        let mut buf = Vec::new();
-        loop {
-            match reader.read_event_into(&mut buf) {
-                Ok(Event::Empty(e)) => {
-                    let name = String::from_utf8_lossy(e.name().as_ref()).to_string();
-                    
-                    if name == "server" {
-                        let attrs: HashMap<String, String> = e
-                            .attributes()
-                            .filter_map(|a| a.ok())
-                            .map(|a| {
-                                (
-                                    String::from_utf8_lossy(a.key.as_ref()).to_string(),
-                                    String::from_utf8_lossy(&a.value).to_string(),
-                                )
-                            })
-                            .collect();
-
-                        let id: u32 = attrs.get("id")
-                            .and_then(|s| s.parse().ok())
-                            .unwrap_or(0);
-
-                        if id == 0 {
-                            continue;
-                        }
+        if servers.is_empty() {
+            return Err(SpeedtestError::ServersRetrieval(format!(
+                "{}: no servers found",
+                path.display()
+            )));
+        }
 
-                        if let Some(ids) = server_ids {
-                            if !ids.contains(&id) {
-                                continue;
-                            }
-                        }
+        for server in &mut servers {
+            server.source = Some(path.display().to_string());
+        }
 
-                        if config.ignore_servers.contains(&id) {
-                            continue;
-                        }
+        self.add_servers(servers, server_ids, exclude)?;
 
-                        if let Some(excl) = exclude {
-                            if excl.contains(&id) {
-                                continue;
-                            }
-                        }
+        if self.servers.is_empty() {
+            return Err(self.no_matched_servers(server_ids, exclude));
+        }
 
-                        let lat: f64 = attrs.get("lat")
-                            .and_then(|s| s.parse().ok())
-                            .unwrap_or(0.0);
-                        let lon: f64 = attrs.get("lon")
-                            .and_then(|s| s.parse().ok())
-                            .unwrap_or(0.0);
-
-                        let d = distance(self.lat_lon.0, self.lat_lon.1, lat, lon);
-
-                        let server = Server {
-                            id,
-                            sponsor: attrs.get("sponsor").cloned().unwrap_or_default(),
-                            name: attrs.get("name").cloned().unwrap_or_default(),
-                            country: attrs.get("country").cloned().unwrap_or_default(),
-                            lat,
-                            lon,
-                            url: attrs.get("url").cloned().unwrap_or_default(),
-                            d,
-                            latency: 0.0,
-                        };
+        if self.debug {
+            eprintln!("Total servers available: {}", self.servers.len());
+        }
+        Ok(&self.servers)
+    }
 
-                        self.servers.entry(id).or_insert_with(Vec::new).push(server);
-                    }
+    /// Keep the servers that pass the id filters and record their distance
+    fn add_servers(
+        &mut self,
+        servers: Vec<Server>,
+        server_ids: Option<&[u32]>,
+        exclude: Option<&[u32]>,
+    ) -> Result<()> {
+        let config = self.config.as_ref()
+            .ok_or_else(|| SpeedtestError::ConfigRetrieval("Config not loaded".to_string()))?;
+
+        for mut server in servers {
+            if let Some(ids) = server_ids {
+                if !ids.contains(&server.id) {
+                    continue;
                 }
-                Ok(Event::Eof) => break,
-                Err(e) => {
-                    return Err(SpeedtestError::ServersRetrieval(format!(
-                        "XML parse error at position {}: {:?}",
-                        reader.buffer_position(),
-                        e
-                    )))
+            }
+
+            if config.ignore_servers.contains(&server.id) {
+                continue;
+            }
+
+            if let Some(excl) = exclude {
+                if excl.contains(&server.id) {
+                    continue;
                 }
-                _ => {}
             }
-            buf.clear();
+
+            server.d = distance(self.lat_lon.0, self.lat_lon.1, server.lat, server.lon);
+            if !self.filter.matches(&server) {
+                continue;
+            }
+            self.servers.entry(server.id).or_default().push(server);
         }
 
         Ok(())
     }
 
+    /// Describe the active selection for a `NoMatchedServers` error
+    fn no_matched_servers(&self, server_ids: Option<&[u32]>, exclude: Option<&[u32]>) -> SpeedtestError {
+        let join = |ids: &[u32]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(", ");
+
+        let mut parts = Vec::new();
+        if let Some(ids) = server_ids {
+            parts.push(format!("server ids {}", join(ids)));
+        }
+        if let Some(ids) = exclude {
+            parts.push(format!("excluding {}", join(ids)));
+        }
+        if !self.filter.is_empty() {
+            parts.push(self.filter.to_string());
+        }
+        if parts.is_empty() {
+            parts.push("every server is in the ignore list".to_string());
+        }
+
+        SpeedtestError::NoMatchedServers(parts.join("; "))
+    }
+
     pub fn get_closest_servers(&mut self, limit: usize) -> Result<&[Server]> {
         if self.servers.is_empty() {
             self.get_servers(None, None)?;
         }
 
+        // Distances may have changed since loading, e.g. with set_location
         let mut all_servers: Vec<Server> = self
             .servers
             .values()
             .flatten()
+            .filter(|s| self.filter.matches(s))
             .cloned()
             .collect();
 
+        if all_servers.is_empty() && !self.filter.is_empty() {
+            return Err(self.no_matched_servers(None, None));
+        }
+
         if self.debug {
             eprintln!("Total servers before sorting: {}", all_servers.len());
         }
 
-        all_servers.sort_by(|a, b| a.d.partial_cmp(&b.d).unwrap());
+        all_servers.sort_by(|a, b| a.d.total_cmp(&b.d));
 
         self.closest = all_servers.into_iter().take(limit).collect();
 
@@ -390,18 +551,24 @@ impl Speedtest {
             s.to_vec()
         } else {
             if self.closest.is_empty() {
-                self.get_closest_servers(5)?;
+                self.get_closest_servers(self.probe.candidates)?;
             }
-            self.closest.clone()
+            self.closest
+                .iter()
+                .take(self.probe.candidates)
+                .cloned()
+                .collect()
         };
 
-        let results: Vec<(f64, Server)> = servers_to_test
-            .par_iter()
-            .filter_map(|server| {
-                let latency = self.measure_latency(server).ok()?;
-                Some((latency, server.clone()))
-            })
-            .collect();
+        let results: Vec<(f64, Option<IpFamily>, Server)> = self.in_probe_pool(|| {
+            servers_to_test
+                .par_iter()
+                .filter_map(|server| {
+                    let (latency, family) = self.probe_latency(server).ok()?;
+                    Some((latency, family, server.clone()))
+                })
+                .collect()
+        });
 
         let best = results
             .into_iter()
@@ -410,88 +577,318 @@ impl Speedtest {
                 "Unable to connect to servers to test latency".to_string()
             ))?;
 
-        let mut best_server = best.1;
+        let mut best_server = best.2;
         best_server.latency = best.0;
+        if self.debug {
+            if let Some(proxy) = self.client.proxy_for(&best_server.url) {
+                eprintln!("Using proxy for tests: {}", proxy);
+            }
+        }
         self.best = Some(best_server);
+        self.ip_family = best.1;
+        self.latency = None;
 
         Ok(self.best.as_ref().unwrap())
     }
 
-    fn measure_latency(&self, server: &Server) -> Result<f64> {
-        let url_parts: Vec<&str> = server.url.split('/').collect();
-        let base_url = url_parts[..url_parts.len() - 1].join("/");
+    /// Measure the latency of every server concurrently
+    ///
+    /// Sets `reachable` on every server, `latency` only on the reachable ones.
+    pub fn ping_servers(&self, servers: &mut [Server]) {
+        self.in_probe_pool(|| {
+            servers.par_iter_mut().for_each(|server| {
+                match self.probe_latency(server) {
+                    Ok((latency, _)) => {
+                        server.latency = latency;
+                        server.reachable = Some(true);
+                    }
+                    Err(_) => {
+                        server.latency = 0.0;
+                        server.reachable = Some(false);
+                    }
+                }
+            });
+        });
+    }
+
+    /// Client and interval for the latency prober, when enabled
+    fn loaded_latency_prober(&self) -> Result<Option<(HttpClient, Duration)>> {
+        match self.loaded_latency {
+            Some(interval) => Ok(Some((self.client.try_clone()?, interval))),
+            None => Ok(None),
+        }
+    }
+
+    /// Run latency probes on a dedicated pool when a concurrency is configured
+    fn in_probe_pool<R: Send>(&self, probe: impl FnOnce() -> R + Send) -> R {
+        let pool = self.probe.concurrency.and_then(|n| {
+            rayon::ThreadPoolBuilder::new().num_threads(n).build().ok()
+        });
+        match pool {
+            Some(pool) => pool.install(probe),
+            None => probe(),
+        }
+    }
+
+    pub fn set_mini_server(&mut self, url: &str) -> Result<&[Server]> {
+        let parsed = url::Url::parse(url)
+            .map_err(|e| SpeedtestError::InvalidMiniServer(format!("{}: {}", url, e)))?;
+        let host = parsed
+            .host_str()
+            .ok_or_else(|| SpeedtestError::InvalidMiniServer(url.to_string()))?
+            .to_string();
+        let base_url = url.trim_end_matches('/');
 
         if self.debug {
-            eprintln!("Testing latency for server: {} ({})", server.sponsor, server.name);
-            eprintln!("  Server URL: {}", server.url);
-            eprintln!("  Base URL: {}", base_url);
+            eprintln!("Probing Speedtest Mini server: {}", base_url);
         }
 
-        let mut latencies = Vec::new();
+        let html = self
+            .client
+            .get_text(base_url)
+            .map_err(|e| SpeedtestError::MiniConnectFailure(format!("{}: {}", url, e)))?;
 
-        for i in 0..3 {
-            use std::time::{SystemTime, UNIX_EPOCH};
-            let timestamp = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_millis();
-            
-            let url = format!("{}/latency.txt?x={}.{}", base_url, timestamp, i);
-            
-            if self.debug {
-                eprintln!("  Attempt {} - Testing URL: {}", i+1, url);
-            }
-            
-            let start = Instant::now();
-            match self.client.get_text(&url) {
-                Ok(response) if response.trim() == "test=test" => {
-                    let latency = start.elapsed().as_secs_f64() * 1000.0;
-                    if self.debug {
-                        eprintln!("  SUCCESS - Latency: {:.3} ms", latency);
-                    }
-                    latencies.push(latency);
-                }
-                Ok(response) => {
-                    if self.debug {
-                        eprintln!("  Unexpected response: '{}'", response.trim());
-                    }
-                    latencies.push(3600.0);
+        let mut extension = parse_mini_extension(&html);
+
+        if extension.is_none() {
+            for ext in MINI_UPLOAD_EXTENSIONS {
+                let probe_url = format!("{}/speedtest/upload.{}", base_url, ext);
+                if self.debug {
+                    eprintln!("  Trying upload handler: {}", probe_url);
                 }
-                Err(e) => {
-                    if self.debug {
-                        eprintln!("  Error: {}", e);
-                    }
-                    latencies.push(3600.0);
+                let response = match self.client.get(&probe_url) {
+                    Ok(response) if response.status().is_success() => response,
+                    _ => continue,
+                };
+                let body = response.text().unwrap_or_default();
+                if is_mini_upload_response(&body) {
+                    extension = Some(ext.to_string());
+                    break;
                 }
             }
         }
 
-        if latencies.iter().all(|&l| l >= 3600.0) {
+        let extension = extension.ok_or_else(|| {
+            SpeedtestError::InvalidMiniServer(format!(
+                "{}: unable to determine upload handler extension",
+                url
+            ))
+        })?;
+
+        if self.debug {
+            eprintln!("  Upload handler extension: {}", extension);
+        }
+
+        let server = Server {
+            id: 0,
+            sponsor: "Speedtest Mini".to_string(),
+            name: host,
+            country: String::new(),
+            cc: String::new(),
+            lat: 0.0,
+            lon: 0.0,
+            url: format!("{}/speedtest/upload.{}", base_url, extension),
+            d: 0.0,
+            latency: 0.0,
+            reachable: None,
+            source: None,
+        };
+
+        self.servers.clear();
+        self.servers.insert(server.id, vec![server.clone()]);
+        self.closest = vec![server.clone()];
+        self.best = Some(server);
+        self.latency = None;
+
+        Ok(&self.closest)
+    }
+
+    /// Measure the latency distribution of the best server with `samples` sequential requests
+    pub fn measure_latency(&mut self, samples: usize) -> Result<&LatencyStats> {
+        let server = self.best.as_ref().ok_or(SpeedtestError::MissingBestServer)?;
+        let (latencies, failures, _) = self.sample_latency(server, samples);
+        let stats = LatencyStats::from_samples(&latencies, failures);
+
+        if self.debug {
+            eprintln!(
+                "Latency over {} samples ({} failed): median {:.3} ms, jitter {:.3} ms",
+                stats.samples, stats.failures, stats.median, stats.jitter
+            );
+        }
+
+        self.latency = Some(stats);
+        Ok(self.latency.as_ref().unwrap())
+    }
+
+    /// Latency used to rank a server: the median of the successful probes
+    fn probe_latency(&self, server: &Server) -> Result<(f64, Option<IpFamily>)> {
+        let (latencies, _, family) = self.sample_latency(server, self.probe.attempts);
+
+        if latencies.is_empty() {
             return Err(SpeedtestError::BestServerFailure(
                 format!("All latency tests failed for {}", server.sponsor)
             ));
         }
 
-        let avg = latencies.iter().sum::<f64>() / latencies.len() as f64;
+        // A single slow or failed probe should not disqualify the server
+        let latency = median(&latencies);
         
         if self.debug {
-            eprintln!("  Average latency: {:.3} ms", avg);
+            eprintln!("  Median latency: {:.3} ms", latency);
         }
         
-        Ok((avg * 1000.0).round() / 1000.0)
+        Ok(((latency * 1000.0).round() / 1000.0, family))
+    }
+
+    /// Request latency.txt `count` times, returning the successful latencies in
+    /// order, the number of failures and the address family that answered
+    fn sample_latency(&self, server: &Server, count: usize) -> (Vec<f64>, usize, Option<IpFamily>) {
+        let base_url = server_base_url(server);
+
+        if self.debug {
+            eprintln!("Testing latency for server: {} ({})", server.sponsor, server.name);
+            eprintln!("  Server URL: {}", server.url);
+            eprintln!("  Base URL: {}", base_url);
+        }
+
+        let mut latencies = Vec::new();
+        let mut failures = 0;
+        let mut family = None;
+
+        for i in 0..count.max(1) {
+            match self.latency_request(&self.client, &base_url, i, self.probe.timeout) {
+                Some((latency, peer_family)) => {
+                    family = peer_family.or(family);
+                    latencies.push(latency);
+                }
+                None => failures += 1,
+            }
+        }
+
+        (latencies, failures, family)
+    }
+
+    /// Time a single latency.txt request, `None` when it fails
+    fn latency_request(
+        &self,
+        client: &HttpClient,
+        base_url: &str,
+        attempt: usize,
+        timeout: Option<Duration>,
+    ) -> Option<(f64, Option<IpFamily>)> {
+        use std::time::{SystemTime, UNIX_EPOCH};
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+
+        let url = format!("{}/latency.txt?x={}.{}", base_url, timestamp, attempt);
+
+        if self.debug {
+            eprintln!("  Attempt {} - Testing URL: {}", attempt + 1, url);
+        }
+
+        let start = Instant::now();
+        let response = match timeout {
+            Some(timeout) => client.get_with_timeout(&url, timeout),
+            None => client.get(&url),
+        };
+        let response = response.and_then(|r| {
+            let peer = r.remote_addr();
+            Ok((r.text()?, peer))
+        });
+        match response {
+            Ok((response, peer)) if response.trim() == "test=test" => {
+                let latency = start.elapsed().as_secs_f64() * 1000.0;
+                if self.debug {
+                    eprintln!("  SUCCESS - Latency: {:.3} ms", latency);
+                }
+                Some((latency, peer.map(|addr| IpFamily::of(&addr.ip()))))
+            }
+            Ok((response, _)) => {
+                if self.debug {
+                    eprintln!("  Unexpected response: '{}'", response.trim());
+                }
+                None
+            }
+            Err(e) => {
+                if self.debug {
+                    eprintln!("  Error: {}", e);
+                }
+                None
+            }
+        }
+    }
+
+    /// Probe latency every `interval` until `stop` is set, for latency under load
+    ///
+    /// Probes time out at `deadline`, when the test window closes.
+    fn probe_under_load(
+        &self,
+        client: &HttpClient,
+        base_url: &str,
+        interval: Duration,
+        deadline: Instant,
+        stop: &AtomicBool,
+    ) -> LatencyStats {
+        let probe_timeout = self.probe.timeout.unwrap_or(LOADED_PROBE_TIMEOUT);
+        let mut latencies = Vec::new();
+        let mut failures = 0;
+        let mut attempt = 0;
+
+        while !stop.load(Ordering::Relaxed) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+
+            let timeout = probe_timeout.min(remaining);
+            let next = Instant::now() + interval;
+            match self.latency_request(client, base_url, attempt, Some(timeout)) {
+                Some((latency, _)) => latencies.push(latency),
+                // A probe cut short by the window closing says nothing about the link
+                None if timeout < probe_timeout => {}
+                None => failures += 1,
+            }
+            attempt += 1;
+
+            // Wait for the next probe, waking up early when the test ends
+            while !stop.load(Ordering::Relaxed) {
+                let now = Instant::now();
+                if now >= next {
+                    break;
+                }
+                std::thread::sleep((next - now).min(Duration::from_millis(20)));
+            }
+        }
+
+        LatencyStats::from_samples(&latencies, failures)
+    }
+
+    pub fn download<F>(&self, callback: F, threads: Option<usize>) -> Result<TransferResult>
+    where
+        F: Fn(usize, usize, bool, bool) + Send + Sync,
+    {
+        self.download_with_progress(callback, |_| {}, threads)
     }
 
-    pub fn download<F>(&self, _callback: F, threads: Option<usize>) -> Result<f64>
+    /// Run the download test, reporting live throughput to `progress`
+    pub fn download_with_progress<F, P>(
+        &self,
+        callback: F,
+        mut progress: P,
+        threads: Option<usize>,
+    ) -> Result<TransferResult>
     where
         F: Fn(usize, usize, bool, bool) + Send + Sync,
+        P: FnMut(&TransferProgress),
     {
         let config = self.config.as_ref()
             .ok_or_else(|| SpeedtestError::ConfigRetrieval("Config not loaded".to_string()))?;
         let server = self.best.as_ref()
             .ok_or(SpeedtestError::MissingBestServer)?;
 
-        let base_url = server.url.split('/').collect::<Vec<_>>();
-        let base_url = base_url[..base_url.len() - 1].join("/");
+        let base_url = server_base_url(server);
 
         let mut urls = Vec::new();
         for size in &config.sizes.download {
@@ -511,61 +908,117 @@ impl Speedtest {
         let max_threads = threads.unwrap_or(config.threads.download);
         let test_duration = Duration::from_secs(config.length.download);
         
-        let total_bytes = Arc::new(AtomicU64::new(0));
-        let start_time = Instant::now();
-        let stop_flag = Arc::new(AtomicBool::new(false));
+        let clients = self.worker_clients(max_threads)?;
+        let prober = self.loaded_latency_prober()?;
+        let probe_url = &base_url;
 
-        // Spawn download threads
-        let handles: Vec<_> = (0..max_threads)
-            .map(|_| {
-                let urls = urls.clone();
-                let total_bytes = Arc::clone(&total_bytes);
-                let stop_flag = Arc::clone(&stop_flag);
-                let client = HttpClient::new(10, false, None).unwrap();
-                
-                std::thread::spawn(move || {
+        let total_bytes = AtomicU64::new(0);
+        let start_time = Instant::now();
+        let deadline = start_time + test_duration;
+        let stop_flag = AtomicBool::new(false);
+
+        let (samples, loaded_latency) = std::thread::scope(|scope| {
+            // Spawn download threads
+            for client in clients {
+                let urls = &urls;
+                let total_bytes = &total_bytes;
+                let stop_flag = &stop_flag;
+                let callback = &callback;
+
+                scope.spawn(move || {
+                    // One buffer per worker, reused for every response
+                    let mut buf = vec![0u8; DOWNLOAD_CHUNK_SIZE];
                     let mut url_index = 0;
                     while !stop_flag.load(Ordering::Relaxed) {
                         // Loop back to start when we reach the end
                         if url_index >= urls.len() {
                             url_index = 0;
                         }
-                        
-                        if let Ok(data) = client.get_bytes(&urls[url_index]) {
-                            total_bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+
+                        // Requests time out when the window closes, even while
+                        // connecting or waiting for the response
+                        let remaining = deadline.saturating_duration_since(Instant::now());
+                        if remaining.is_zero() {
+                            break;
                         }
-                        
+
+                        callback(url_index, urls.len(), true, false);
+                        // Count the body as it arrives and drop the
+                        // connection as soon as the window closes
+                        let _ = client.get_discard(&urls[url_index], &mut buf, remaining, |n| {
+                            if stop_flag.load(Ordering::Relaxed) {
+                                return false;
+                            }
+                            total_bytes.fetch_add(n as u64, Ordering::Relaxed);
+                            true
+                        });
+                        callback(url_index, urls.len(), false, true);
+
                         url_index += 1;
                     }
+                });
+            }
+
+            let prober = prober.map(|(client, interval)| {
+                let probe_url = &probe_url;
+                let stop_flag = &stop_flag;
+                scope.spawn(move || {
+                    self.probe_under_load(&client, probe_url, interval, deadline, stop_flag)
                 })
-            })
-            .collect();
+            });
 
-        // Monitor progress
-        std::thread::sleep(test_duration);
-        stop_flag.store(true, Ordering::Relaxed);
+            let samples = monitor_transfer(&total_bytes, start_time, test_duration, &mut progress);
+            stop_flag.store(true, Ordering::Relaxed);
 
-        for handle in handles {
-            let _ = handle.join();
-        }
+            (samples, prober.and_then(|handle| handle.join().ok()))
+        });
 
-        let elapsed = start_time.elapsed().as_secs_f64();
-        let bytes = total_bytes.load(Ordering::Relaxed);
-        let speed = (bytes as f64 / elapsed) * 8.0;
+        // Only what was transferred inside the window counts
+        let window = samples.last().copied().unwrap_or(ThroughputSample { elapsed: 0.0, bytes: 0 });
+        let elapsed = window.elapsed;
+        let bytes = window.bytes;
+        let average_speed = if elapsed > 0.0 { (bytes as f64 / elapsed) * 8.0 } else { 0.0 };
+        let speed = steady_state_speed(&samples).unwrap_or(average_speed);
 
         if self.debug {
             eprintln!("Download test results:");
             eprintln!("  Bytes downloaded: {}", bytes);
             eprintln!("  Time elapsed: {:.2} seconds", elapsed);
             eprintln!("  Speed: {:.2} bits/s ({:.2} Mbit/s)", speed, speed / 1_000_000.0);
+            eprintln!("  Average speed: {:.2} Mbit/s over {} samples", average_speed / 1_000_000.0, samples.len());
+            if let Some(ref stats) = loaded_latency {
+                eprintln!("  Loaded latency: median {:.3} ms ({} failed)", stats.median, stats.failures);
+            }
         }
 
-        Ok(speed)
+        Ok(TransferResult {
+            bytes,
+            elapsed,
+            speed,
+            average_speed,
+            samples,
+            loaded_latency,
+        })
+    }
+
+    pub fn upload<F>(&self, callback: F, threads: Option<usize>, pre_allocate: bool) -> Result<TransferResult>
+    where
+        F: Fn(usize, usize, bool, bool) + Send + Sync,
+    {
+        self.upload_with_progress(callback, |_| {}, threads, pre_allocate)
     }
 
-    pub fn upload<F>(&self, _callback: F, threads: Option<usize>, _pre_allocate: bool) -> Result<f64>
+    /// Run the upload test, reporting live throughput to `progress`
+    pub fn upload_with_progress<F, P>(
+        &self,
+        callback: F,
+        mut progress: P,
+        threads: Option<usize>,
+        _pre_allocate: bool,
+    ) -> Result<TransferResult>
     where
         F: Fn(usize, usize, bool, bool) + Send + Sync,
+        P: FnMut(&TransferProgress),
     {
         let config = self.config.as_ref()
             .ok_or_else(|| SpeedtestError::ConfigRetrieval("Config not loaded".to_string()))?;
@@ -590,82 +1043,457 @@ impl Speedtest {
         let max_threads = threads.unwrap_or(config.threads.upload);
         let test_duration = Duration::from_secs(config.length.upload);
         
-        let total_bytes = Arc::new(AtomicU64::new(0));
-        let start_time = Instant::now();
-        let stop_flag = Arc::new(AtomicBool::new(false));
+        let clients = self.worker_clients(max_threads)?;
+
+        let prober = self.loaded_latency_prober()?;
+        let probe_url = server_base_url(server);
 
         // Generate upload data
         let chars = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
-        let upload_data: Vec<Vec<u8>> = sizes
+        let upload_data: Vec<Arc<[u8]>> = sizes
             .iter()
             .map(|&size| {
                 let multiplier = (size as f64 / 36.0).round() as usize;
                 let content = chars.repeat(multiplier);
                 let data = format!("content1={}", &content[..size.min(content.len()) - 9]);
-                data.into_bytes()
+                Arc::from(data.into_bytes())
             })
             .collect();
 
-        let handles: Vec<_> = (0..max_threads)
-            .map(|i| {
-                let data_chunk = upload_data.clone();
-                let url = server.url.clone();
-                let total_bytes = Arc::clone(&total_bytes);
-                let stop_flag = Arc::clone(&stop_flag);
-                let client = HttpClient::new(10, false, None).unwrap();
-                
-                std::thread::spawn(move || {
+        // Shared with the request bodies, which outlive the worker's borrow
+        let total_bytes = Arc::new(AtomicU64::new(0));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let start_time = Instant::now();
+        let deadline = start_time + test_duration;
+
+        let (samples, loaded_latency) = std::thread::scope(|scope| {
+            for (i, client) in clients.into_iter().enumerate() {
+                let data_chunk = &upload_data;
+                let url = &server.url;
+                let total_bytes = &total_bytes;
+                let stop_flag = &stop_flag;
+                let callback = &callback;
+
+                scope.spawn(move || {
                     let mut data_index = i;
                     while !stop_flag.load(Ordering::Relaxed) {
                         // Loop back to start when we reach the end
                         if data_index >= data_chunk.len() {
-                            data_index = i; // Start from this thread's offset again
+                            // Start from this thread's offset again
+                            data_index = i % data_chunk.len();
                         }
-                        
-                        if let Ok(_) = client.post(&url, data_chunk[data_index].clone()) {
-                            total_bytes.fetch_add(data_chunk[data_index].len() as u64, Ordering::Relaxed);
+
+                        // Requests time out when the window closes, even while
+                        // waiting for the upload response
+                        let remaining = deadline.saturating_duration_since(Instant::now());
+                        if remaining.is_zero() {
+                            break;
                         }
-                        
+
+                        callback(data_index, data_chunk.len(), true, false);
+                        let data = &data_chunk[data_index];
+                        let body = WindowReader {
+                            data: Arc::clone(data),
+                            pos: 0,
+                            total_bytes: Arc::clone(total_bytes),
+                            stop_flag: Arc::clone(stop_flag),
+                        };
+                        let _ = client.post_reader(url, body, data.len() as u64, remaining);
+                        callback(data_index, data_chunk.len(), false, true);
+
                         data_index += max_threads;
                     }
+                });
+            }
+
+            let prober = prober.map(|(client, interval)| {
+                let probe_url = &probe_url;
+                let stop_flag = &stop_flag;
+                scope.spawn(move || {
+                    self.probe_under_load(&client, probe_url, interval, deadline, stop_flag)
                 })
-            })
-            .collect();
+            });
 
-        // Monitor progress
-        std::thread::sleep(test_duration);
-        stop_flag.store(true, Ordering::Relaxed);
+            let samples = monitor_transfer(&total_bytes, start_time, test_duration, &mut progress);
+            stop_flag.store(true, Ordering::Relaxed);
 
-        for handle in handles {
-            let _ = handle.join();
-        }
+            (samples, prober.and_then(|handle| handle.join().ok()))
+        });
 
-        let elapsed = start_time.elapsed().as_secs_f64();
-        let bytes = total_bytes.load(Ordering::Relaxed);
-        let speed = (bytes as f64 / elapsed) * 8.0;
+        // Only what was transferred inside the window counts
+        let window = samples.last().copied().unwrap_or(ThroughputSample { elapsed: 0.0, bytes: 0 });
+        let elapsed = window.elapsed;
+        let bytes = window.bytes;
+        let average_speed = if elapsed > 0.0 { (bytes as f64 / elapsed) * 8.0 } else { 0.0 };
+        let speed = steady_state_speed(&samples).unwrap_or(average_speed);
 
         if self.debug {
             eprintln!("Upload test results:");
             eprintln!("  Bytes uploaded: {}", bytes);
             eprintln!("  Time elapsed: {:.2} seconds", elapsed);
             eprintln!("  Speed: {:.2} bits/s ({:.2} Mbit/s)", speed, speed / 1_000_000.0);
+            eprintln!("  Average speed: {:.2} Mbit/s over {} samples", average_speed / 1_000_000.0, samples.len());
+            if let Some(ref stats) = loaded_latency {
+                eprintln!("  Loaded latency: median {:.3} ms ({} failed)", stats.median, stats.failures);
+            }
         }
 
-        Ok(speed)
+        Ok(TransferResult {
+            bytes,
+            elapsed,
+            speed,
+            average_speed,
+            samples,
+            loaded_latency,
+        })
+    }
+
+    /// Build one HTTP client per worker thread, sharing the main client's settings
+    fn worker_clients(&self, count: usize) -> Result<Vec<HttpClient>> {
+        (0..count).map(|_| self.client.try_clone()).collect()
     }
 
     pub fn get_results(&self) -> Option<SpeedtestResults> {
         let config = self.config.as_ref()?;
         let server = self.best.as_ref()?;
 
-        Some(SpeedtestResults::new(
+        let mut results = SpeedtestResults::new(
             config.client.clone(),
             server.clone(),
-        ))
+        );
+        results.interface = self.client.options().interface.clone();
+        results.local_address = self.client.local_address().map(|ip| ip.to_string());
+        results.ip_family = self.client.options().ip_family.or(self.ip_family);
+        results.proxy = self.client.proxy_for(&server.url);
+        results.latency = self.latency;
+
+        Some(results)
     }
 }
 
-use crate::utils::cache_buster;
+/// Parse a speedtest.net configuration document
+fn parse_config_xml(xml: &str) -> Result<Config> {
+    // Parse XML manually to extract attributes
+    use quick_xml::events::Event;
+    use quick_xml::Reader;
+    use std::collections::HashMap;
+
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+
+    let mut client_attrs: HashMap<String, String> = HashMap::new();
+    let mut server_config_attrs: HashMap<String, String> = HashMap::new();
+    let mut download_attrs: HashMap<String, String> = HashMap::new();
+    let mut upload_attrs: HashMap<String, String> = HashMap::new();
+
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(Event::Empty(e)) => {
+                let name = String::from_utf8_lossy(e.name().as_ref()).to_string();
+                
+                let attrs: HashMap<String, String> = e
+                    .attributes()
+                    .filter_map(|a| a.ok())
+                    .map(|a| {
+                        (
+                            String::from_utf8_lossy(a.key.as_ref()).to_string(),
+                            String::from_utf8_lossy(&a.value).to_string(),
+                        )
+                    })
+                    .collect();
+
+                match name.as_str() {
+                    "client" => client_attrs = attrs,
+                    "server-config" => server_config_attrs = attrs,
+                    "download" => download_attrs = attrs,
+                    "upload" => upload_attrs = attrs,
+                    _ => {}
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => {
+                return Err(SpeedtestError::ConfigRetrieval(format!(
+                    "XML parse error at position {}: {:?}",
+                    reader.buffer_position(),
+                    e
+                )))
+            }
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    // Build Client from attributes
+    let client = Client {
+        ip: client_attrs.get("ip").cloned().unwrap_or_default(),
+        lat: client_attrs.get("lat").cloned().unwrap_or_default(),
+        lon: client_attrs.get("lon").cloned().unwrap_or_default(),
+        isp: client_attrs.get("isp").cloned().unwrap_or_default(),
+        country: client_attrs.get("country").cloned().unwrap_or_default(),
+        isprating: client_attrs.get("isprating").cloned().unwrap_or_default(),
+        rating: client_attrs.get("rating").cloned().unwrap_or_default(),
+        ispdlavg: client_attrs.get("ispdlavg").cloned().unwrap_or_default(),
+        ispulavg: client_attrs.get("ispulavg").cloned().unwrap_or_default(),
+        loggedin: client_attrs.get("loggedin").cloned().unwrap_or_default(),
+    };
+
+    // Validate client data
+    if client.ip.is_empty() {
+        return Err(SpeedtestError::ConfigRetrieval(
+            "Client IP address not provided by server".to_string()
+        ));
+    }
+
+    let ignore_servers: Vec<u32> = server_config_attrs
+        .get("ignoreids")
+        .unwrap_or(&String::new())
+        .split(',')
+        .filter_map(|s| s.trim().parse().ok())
+        .collect();
+
+    let ratio: usize = upload_attrs
+        .get("ratio")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_UPLOAD_RATIO);
+    
+    let upload_max: usize = upload_attrs
+        .get("maxchunkcount")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_UPLOAD_MAX_CHUNKS);
+
+    let upload_sizes = Sizes::upload_for_ratio(ratio);
+
+    let size_count = upload_sizes.len();
+    let upload_count = Counts::upload_for(upload_max, size_count);
+
+    let config = Config {
+        client,
+        ignore_servers,
+        sizes: Sizes {
+            upload: upload_sizes,
+            download: DOWNLOAD_SIZES.to_vec(),
+        },
+        counts: Counts {
+            upload: upload_count,
+            download: download_attrs
+                .get("threadsperurl")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(DEFAULT_DOWNLOAD_COUNT),
+        },
+        threads: Threads {
+            upload: upload_attrs
+                .get("threads")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(DEFAULT_UPLOAD_THREADS),
+            download: server_config_attrs
+                .get("threadcount")
+                .and_then(|s| s.parse::<usize>().ok())
+                .unwrap_or(DEFAULT_THREAD_COUNT)
+                * 2,
+        },
+        length: Length {
+            upload: upload_attrs
+                .get("testlength")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(DEFAULT_TEST_LENGTH),
+            download: download_attrs
+                .get("testlength")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(DEFAULT_TEST_LENGTH),
+        },
+        upload_max: upload_count * size_count,
+    };
+
+    Ok(config)
+}
+
+/// Parse a speedtest.net server list document
+fn parse_servers_xml(xml: &str) -> Result<Vec<Server>> {
+    use quick_xml::events::Event;
+    use quick_xml::Reader;
+
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+
+    let mut servers = Vec::new();
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(Event::Empty(e)) => {
+                let name = String::from_utf8_lossy(e.name().as_ref()).to_string();
+
+                if name == "server" {
+                    let attrs: HashMap<String, String> = e
+                        .attributes()
+                        .filter_map(|a| a.ok())
+                        .map(|a| {
+                            (
+                                String::from_utf8_lossy(a.key.as_ref()).to_string(),
+                                String::from_utf8_lossy(&a.value).to_string(),
+                            )
+                        })
+                        .collect();
+
+                    let id: u32 = attrs.get("id")
+                        .and_then(|s| s.parse().ok())
+                        .unwrap_or(0);
+
+                    if id == 0 {
+                        continue;
+                    }
+
+                    servers.push(Server {
+                        id,
+                        sponsor: attrs.get("sponsor").cloned().unwrap_or_default(),
+                        name: attrs.get("name").cloned().unwrap_or_default(),
+                        country: attrs.get("country").cloned().unwrap_or_default(),
+                        cc: attrs.get("cc").cloned().unwrap_or_default(),
+                        lat: attrs.get("lat").and_then(|s| s.parse().ok()).unwrap_or(0.0),
+                        lon: attrs.get("lon").and_then(|s| s.parse().ok()).unwrap_or(0.0),
+                        url: attrs.get("url").cloned().unwrap_or_default(),
+                        d: 0.0,
+                        latency: 0.0,
+                        reachable: None,
+                        source: None,
+                    });
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => {
+                return Err(SpeedtestError::ServersRetrieval(format!(
+                    "XML parse error at position {}: {:?}",
+                    reader.buffer_position(),
+                    e
+                )))
+            }
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    Ok(servers)
+}
+
+/// Interval between progress reports while a transfer is running
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Timeout of latency probes sent while the link is loaded, unless one is configured
+const LOADED_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Size of the buffer each download worker reads response bodies into
+const DOWNLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Upload body that counts bytes as they are sent while the test window is open
+///
+/// Reading fails once the window closes, which aborts the request.
+struct WindowReader {
+    data: Arc<[u8]>,
+    pos: usize,
+    total_bytes: Arc<AtomicU64>,
+    stop_flag: Arc<AtomicBool>,
+}
+
+impl std::io::Read for WindowReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.stop_flag.load(Ordering::Relaxed) {
+            return Err(std::io::Error::other("test window closed"));
+        }
+        let n = buf.len().min(self.data.len() - self.pos);
+        buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+        self.pos += n;
+        self.total_bytes.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+/// Directory of the server's upload handler, where latency.txt and the images live
+fn server_base_url(server: &Server) -> String {
+    let url_parts: Vec<&str> = server.url.split('/').collect();
+    url_parts[..url_parts.len() - 1].join("/")
+}
+
+/// Wait out the test window, reporting transfer progress at a fixed interval
+///
+/// Returns the bytes transferred over time, one sample per interval.
+fn monitor_transfer<P>(
+    total_bytes: &AtomicU64,
+    start_time: Instant,
+    test_duration: Duration,
+    progress: &mut P,
+) -> Vec<ThroughputSample>
+where
+    P: FnMut(&TransferProgress),
+{
+    let mut last_bytes = 0;
+    let mut last_time = start_time;
+    let mut samples = vec![ThroughputSample {
+        elapsed: 0.0,
+        bytes: 0,
+    }];
+
+    loop {
+        let elapsed = start_time.elapsed();
+        if elapsed >= test_duration {
+            break;
+        }
+        std::thread::sleep(PROGRESS_INTERVAL.min(test_duration - elapsed));
+
+        let now = Instant::now();
+        let bytes = total_bytes.load(Ordering::Relaxed);
+        let interval = now.duration_since(last_time).as_secs_f64();
+        let rate = if interval > 0.0 {
+            (bytes.saturating_sub(last_bytes) as f64 / interval) * 8.0
+        } else {
+            0.0
+        };
+
+        let elapsed = now.duration_since(start_time);
+        progress(&TransferProgress {
+            bytes,
+            rate,
+            elapsed,
+            duration: test_duration,
+        });
+        samples.push(ThroughputSample {
+            elapsed: elapsed.as_secs_f64(),
+            bytes,
+        });
+
+        last_bytes = bytes;
+        last_time = now;
+    }
+
+    samples
+}
+
+const MINI_UPLOAD_EXTENSIONS: [&str; 4] = ["php", "asp", "aspx", "jsp"];
+
+/// Extract the upload handler extension advertised by a Speedtest Mini page
+fn parse_mini_extension(html: &str) -> Option<String> {
+    for key in ["uploadExtension", "upload_extension", "uploadextension", "upload_Extension"] {
+        let needle = format!("{}: \"", key);
+        if let Some(pos) = html.find(&needle) {
+            let rest = &html[pos + needle.len()..];
+            if let Some(end) = rest.find('"') {
+                if end > 0 {
+                    return Some(rest[..end].to_string());
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Check whether a body looks like a Speedtest Mini upload handler reply (`size=N`)
+fn is_mini_upload_response(body: &str) -> bool {
+    let body = body.trim();
+    body.lines().count() == 1
+        && body
+            .strip_prefix("size=")
+            .and_then(|rest| rest.chars().next())
+            .is_some_and(|c| c.is_ascii_digit())
+}
 
 #[cfg(test)]
 mod tests {
@@ -676,4 +1504,408 @@ mod tests {
         let st = Speedtest::new(10, false, None);
         assert!(st.is_ok());
     }
+
+    #[test]
+    fn test_parse_mini_extension() {
+        let html = r#"<script>var uploadExtension: "php";</script>"#;
+        assert_eq!(parse_mini_extension(html), Some("php".to_string()));
+        assert_eq!(parse_mini_extension("<html></html>"), None);
+
+        assert!(is_mini_upload_response("size=0\n"));
+        assert!(!is_mini_upload_response("<html>size=0</html>\nmore"));
+        assert!(!is_mini_upload_response("size=abc"));
+    }
+
+    #[test]
+    fn test_set_mini_server_errors() {
+        let mut st = Speedtest::new(2, false, None).unwrap();
+        assert!(matches!(
+            st.set_mini_server("not a url"),
+            Err(SpeedtestError::InvalidMiniServer(_))
+        ));
+        assert!(matches!(
+            st.set_mini_server("http://127.0.0.1:1/"),
+            Err(SpeedtestError::MiniConnectFailure(_))
+        ));
+    }
+
+    #[test]
+    fn test_monitor_transfer_reports_progress() {
+        let total_bytes = AtomicU64::new(1_000_000);
+        let mut reports = Vec::new();
+        let samples = monitor_transfer(
+            &total_bytes,
+            Instant::now(),
+            Duration::from_millis(500),
+            &mut |p: &TransferProgress| reports.push(*p),
+        );
+
+        assert!(reports.len() >= 2);
+        assert_eq!(samples.len(), reports.len() + 1);
+        assert_eq!(samples[0].elapsed, 0.0);
+        assert!(reports[0].rate > 0.0);
+        assert_eq!(reports.last().unwrap().bytes, 1_000_000);
+        assert!(reports.last().unwrap().elapsed >= Duration::from_millis(500));
+    }
+
+    fn test_config() -> Config {
+        Config {
+            client: Client::default(),
+            ignore_servers: vec![3],
+            sizes: Sizes {
+                upload: vec![32768],
+                download: vec![350],
+            },
+            counts: Counts { upload: 1, download: 1 },
+            threads: Threads { upload: 1, download: 1 },
+            length: Length { upload: 1, download: 1 },
+            upload_max: 1,
+        }
+    }
+
+    #[test]
+    fn test_load_servers_file() {
+        let dir = std::env::temp_dir();
+        let xml_path = dir.join(format!("speedtest-servers-{}.xml", std::process::id()));
+        let json_path = dir.join(format!("speedtest-servers-{}.json", std::process::id()));
+        std::fs::write(
+            &xml_path,
+            r#"<settings><servers>
+                <server url="http://a.example/speedtest/upload.php" lat="10" lon="10" name="A" country="X" sponsor="A" id="1"/>
+                <server url="http://b.example/speedtest/upload.php" lat="20" lon="20" name="B" country="Y" cc="YY" sponsor="B" id="2"/>
+                <server url="http://c.example/speedtest/upload.php" lat="30" lon="30" name="C" country="Z" sponsor="C" id="3"/>
+            </servers></settings>"#,
+        )
+        .unwrap();
+        std::fs::write(
+            &json_path,
+            r#"[{"id": 7, "sponsor": "Lab", "name": "Rack 1", "country": "Local", "lat": 0.0, "lon": 0.0, "url": "http://lab/speedtest/upload.php"}]"#,
+        )
+        .unwrap();
+
+        let mut st = Speedtest::new(10, false, None).unwrap();
+        st.set_config(test_config());
+
+        // id 3 is in the config ignore list
+        let servers = st.load_servers_file(&xml_path, None, None).unwrap();
+        assert_eq!(servers.len(), 2);
+        assert!(servers[&2][0].d > servers[&1][0].d);
+
+        let servers = st.load_servers_file(&xml_path, None, Some(&[1])).unwrap();
+        assert_eq!(servers.keys().collect::<Vec<_>>(), vec![&2]);
+
+        let servers = st.load_servers_file(&json_path, None, None).unwrap();
+        assert_eq!(servers[&7][0].sponsor, "Lab");
+
+        assert!(matches!(
+            st.load_servers_file(&json_path, Some(&[99]), None),
+            Err(SpeedtestError::NoMatchedServers(_))
+        ));
+
+        for country in ["y", "yy"] {
+            st.set_server_filter(ServerFilter {
+                countries: vec![country.to_string()],
+                ..ServerFilter::default()
+            });
+            let servers = st.load_servers_file(&xml_path, None, None).unwrap();
+            assert_eq!(servers.keys().collect::<Vec<_>>(), vec![&2]);
+            assert_eq!(servers[&2][0].cc, "YY");
+        }
+
+        st.set_server_filter(ServerFilter {
+            max_distance: Some(1.0),
+            ..ServerFilter::default()
+        });
+        match st.load_servers_file(&xml_path, None, Some(&[1])) {
+            Err(SpeedtestError::NoMatchedServers(desc)) => {
+                assert_eq!(desc, "excluding 1; at most 1 km away")
+            }
+            other => panic!("unexpected result: {:?}", other.map(|s| s.len())),
+        }
+
+        let _ = std::fs::remove_file(&xml_path);
+        let _ = std::fs::remove_file(&json_path);
+    }
+
+    #[test]
+    fn test_set_location_overrides_config() {
+        let path = std::env::temp_dir()
+            .join(format!("speedtest-location-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"[{"id": 1, "sponsor": "Near", "name": "A", "country": "X", "lat": 0.0, "lon": 0.0, "url": "http://a/speedtest/upload.php"},
+                {"id": 2, "sponsor": "Far", "name": "B", "country": "Y", "lat": 50.0, "lon": 10.0, "url": "http://b/speedtest/upload.php"}]"#,
+        )
+        .unwrap();
+
+        let mut st = Speedtest::new(10, false, None).unwrap();
+        st.set_config(test_config());
+        st.load_servers_file(&path, None, None).unwrap();
+        assert_eq!(st.get_closest_servers(1).unwrap()[0].id, 1);
+
+        assert!(matches!(st.set_location(f64::NAN, 10.0), Err(SpeedtestError::InvalidLocation(_))));
+        assert!(matches!(st.set_location(50.0, 181.0), Err(SpeedtestError::InvalidLocation(_))));
+        assert_eq!(st.get_closest_servers(1).unwrap()[0].id, 1);
+
+        st.set_location(50.0, 10.0).unwrap();
+        assert_eq!(st.get_closest_servers(1).unwrap()[0].id, 2);
+        assert!(st.closest[0].d < 1.0);
+
+        // A config geolocated elsewhere no longer moves the client
+        let mut config = test_config();
+        config.client.lat = "0".to_string();
+        config.client.lon = "0".to_string();
+        st.set_config(config);
+        assert_eq!(st.lat_lon(), (50.0, 10.0));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_use_local_config() {
+        let mut st = Speedtest::new(10, false, Some("127.0.0.1".to_string())).unwrap();
+        let config = st.use_local_config().unwrap();
+        assert_eq!(config.client.ip, "127.0.0.1");
+        assert_eq!(config.threads.download, 8);
+        assert_eq!(config.upload_max, config.sizes.upload.len());
+
+        // Matches what a config without any test settings parses to
+        let parsed = parse_config_xml(r#"<settings><client ip="127.0.0.1"/></settings>"#).unwrap();
+        assert_eq!(config.sizes.upload, parsed.sizes.upload);
+        assert_eq!(config.sizes.download, parsed.sizes.download);
+        assert_eq!((config.counts.upload, config.counts.download), (parsed.counts.upload, parsed.counts.download));
+        assert_eq!((config.threads.upload, config.threads.download), (parsed.threads.upload, parsed.threads.download));
+        assert_eq!((config.length.upload, config.length.download), (parsed.length.upload, parsed.length.download));
+        assert_eq!(config.upload_max, parsed.upload_max);
+    }
+
+    #[test]
+    fn test_stale_cache_fallback() {
+        let dir = std::env::temp_dir().join(format!("speedtest-stale-{}", std::process::id()));
+        let mut st = Speedtest::new(2, false, None).unwrap();
+        st.set_endpoints(Endpoints {
+            config_url: "http://127.0.0.1:1/speedtest-config.php".to_string(),
+            server_urls: vec!["http://127.0.0.1:1/speedtest-servers.php".to_string()],
+            ..Endpoints::default()
+        });
+
+        let cache = Cache::new(&dir, Duration::ZERO);
+        let mut config = test_config();
+        config.client.ip = "192.0.2.1".to_string();
+        cache.put(&st.config_cache_key(), &config).unwrap();
+        cache.put(
+            &st.servers_cache_key(),
+            &parse_servers_xml(
+                r#"<settings><servers><server url="http://a.example/speedtest/upload.php" lat="1" lon="1" name="A" country="X" sponsor="A" id="1"/></servers></settings>"#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        assert!(st.get_config().is_err());
+
+        st.set_cache(Some(cache));
+        assert_eq!(st.get_config().unwrap().client.ip, "192.0.2.1");
+        assert_eq!(st.get_servers(None, None).unwrap().len(), 1);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Serve the same body to every request and return its URL
+    fn serve_body(body: &'static str) -> String {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf);
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+            }
+        });
+        format!("http://{}/speedtest-servers.php", addr)
+    }
+
+    #[test]
+    fn test_merge_servers() {
+        let first = serve_body(
+            r#"<settings><servers>
+                <server url="http://a.example/speedtest/upload.php" lat="1" lon="1" name="A" country="X" sponsor="A" id="1"/>
+                <server url="http://b.example/speedtest/upload.php" lat="2" lon="2" name="B" country="X" sponsor="B" id="2"/>
+            </servers></settings>"#,
+        );
+        let second = serve_body(
+            r#"<settings><servers>
+                <server url="http://b.example/speedtest/upload.php" lat="2" lon="2" name="B" country="X" sponsor="B" id="2"/>
+                <server url="http://d.example/speedtest/upload.php" lat="4" lon="4" name="D" country="X" sponsor="D" id="4"/>
+            </servers></settings>"#,
+        );
+
+        let mut st = Speedtest::new(5, false, None).unwrap();
+        st.set_config(test_config());
+        st.set_endpoints(Endpoints {
+            server_urls: vec![
+                "http://127.0.0.1:1/speedtest-servers.php".to_string(),
+                first.clone(),
+                second.clone(),
+            ],
+            ..Endpoints::default()
+        });
+
+        assert_eq!(st.get_servers(None, None).unwrap().len(), 2);
+
+        st.set_merge_servers(true);
+        let servers = st.get_servers(None, None).unwrap();
+        assert_eq!(servers.len(), 3);
+        assert_eq!(servers[&2].len(), 1);
+        assert_eq!(servers[&2][0].source.as_deref(), Some(first.as_str()));
+        assert_eq!(servers[&4][0].source.as_deref(), Some(second.as_str()));
+    }
+
+    #[test]
+    fn test_cached_servers_without_match_are_refetched() {
+        let first = serve_body(
+            r#"<settings><servers>
+                <server url="http://a.example/speedtest/upload.php" lat="1" lon="1" name="A" country="X" sponsor="A" id="1"/>
+            </servers></settings>"#,
+        );
+        let second = serve_body(
+            r#"<settings><servers>
+                <server url="http://d.example/speedtest/upload.php" lat="4" lon="4" name="D" country="X" sponsor="D" id="4"/>
+            </servers></settings>"#,
+        );
+
+        let dir = std::env::temp_dir().join(format!("speedtest-refetch-{}", std::process::id()));
+        let mut st = Speedtest::new(5, false, None).unwrap();
+        st.set_config(test_config());
+        st.set_cache(Some(Cache::new(&dir, Duration::from_secs(3600))));
+        st.set_endpoints(Endpoints {
+            server_urls: vec![first, second.clone()],
+            ..Endpoints::default()
+        });
+
+        // Caches the first list, then a server only on the second one is still found
+        assert!(st.get_servers(None, None).unwrap().contains_key(&1));
+        let servers = st.get_servers(Some(&[4]), None).unwrap();
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[&4][0].source.as_deref(), Some(second.as_str()));
+
+        // The cache still holds the list an unfiltered run uses
+        let cached = Cache::new(&dir, Duration::from_secs(3600))
+            .get::<Vec<Server>>(&st.servers_cache_key())
+            .unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(cached[0].id, 1);
+    }
+
+    #[test]
+    fn test_probe_timeout_uses_median() {
+        use std::io::{Read, Write};
+
+        // The second latency request stalls past the probe timeout
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for (i, stream) in listener.incoming().flatten().enumerate() {
+                std::thread::spawn(move || {
+                    let mut stream = stream;
+                    let mut buf = [0u8; 4096];
+                    let _ = stream.read(&mut buf);
+                    if i == 1 {
+                        std::thread::sleep(Duration::from_secs(2));
+                    }
+                    let _ = stream.write_all(
+                        b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\nConnection: close\r\n\r\ntest=test",
+                    );
+                });
+            }
+        });
+
+        let mut server = parse_servers_xml(
+            r#"<settings><servers><server url="http://a/speedtest/upload.php" lat="0" lon="0" name="A" country="X" sponsor="A" id="1"/></servers></settings>"#,
+        )
+        .unwrap()
+        .remove(0);
+        server.url = format!("http://{}/speedtest/upload.php", addr);
+
+        let mut st = Speedtest::new(10, false, None).unwrap();
+        st.set_probe_options(ProbeOptions {
+            attempts: 3,
+            timeout: Some(Duration::from_millis(300)),
+            concurrency: Some(1),
+            ..ProbeOptions::default()
+        });
+
+        let start = Instant::now();
+        let best = st.get_best_server(Some(&[server])).unwrap();
+        assert!(best.latency < 300.0);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_window_close_stops_stalled_requests() {
+        use std::io::{Read, Write};
+
+        // The first latency request is answered, the loaded latency probes
+        // and transfers never get response headers
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut answered = false;
+            for stream in listener.incoming().flatten() {
+                let mut stream = stream;
+                let mut buf = [0u8; 4096];
+                let n = stream.read(&mut buf).unwrap_or(0);
+                let latency = String::from_utf8_lossy(&buf[..n]).contains("latency.txt");
+                let answer = latency && !answered;
+                answered |= latency;
+                std::thread::spawn(move || {
+                    if !answer {
+                        std::thread::sleep(Duration::from_secs(30));
+                        return;
+                    }
+                    let _ = stream.write_all(
+                        b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\nConnection: close\r\n\r\ntest=test",
+                    );
+                });
+            }
+        });
+
+        let mut server = parse_servers_xml(
+            r#"<settings><servers><server url="http://a/speedtest/upload.php" lat="0" lon="0" name="A" country="X" sponsor="A" id="1"/></servers></settings>"#,
+        )
+        .unwrap()
+        .remove(0);
+        server.url = format!("http://{}/speedtest/upload.php", addr);
+
+        // The HTTP and probe timeouts are far longer than the 1 second window
+        let mut st = Speedtest::new(30, false, None).unwrap();
+        st.set_config(test_config());
+        st.set_probe_options(ProbeOptions {
+            attempts: 1,
+            timeout: Some(Duration::from_secs(10)),
+            ..ProbeOptions::default()
+        });
+        st.get_best_server(Some(&[server])).unwrap();
+        st.set_loaded_latency(Some(Duration::from_millis(100)));
+
+        let start = Instant::now();
+        let download = st.download(|_, _, _, _| {}, Some(2)).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(3), "took {:?}", start.elapsed());
+        assert_eq!(download.bytes, 0);
+
+        // The probe cut off by the window is not counted as a failure
+        let loaded = download.loaded_latency.unwrap();
+        assert_eq!((loaded.samples, loaded.failures), (0, 0));
+
+        let start = Instant::now();
+        st.upload(|_, _, _, _| {}, Some(2), true).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(3), "took {:?}", start.elapsed());
+    }
 }
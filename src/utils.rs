@@ -38,6 +38,23 @@ pub fn cache_buster() -> String {
     format!("{}", timestamp)
 }
 
+/// Median of a list of values, 0.0 when empty
+pub fn median(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let mid = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -64,4 +81,11 @@ mod tests {
         let cb2 = cache_buster();
         assert_ne!(cb1, cb2);
     }
+
+    #[test]
+    fn test_median() {
+        assert_eq!(median(&[]), 0.0);
+        assert_eq!(median(&[12.0, 3600.0, 10.0]), 12.0);
+        assert_eq!(median(&[4.0, 1.0, 3.0, 2.0]), 2.5);
+    }
 }